@@ -0,0 +1,267 @@
+use std::fs;
+
+use anyhow::{Context, anyhow};
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use sha2::{Digest, Sha256};
+
+use crate::xenium::client::GoogleSheetsClient;
+
+const GOOGLE_SHEETS_SUBDIR: &str = "google-sheets";
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// How long a downloaded spreadsheet is considered fresh before it is downloaded again
+    max_age_minutes: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self { max_age_minutes: 60 }
+    }
+}
+
+#[derive(Clone)]
+pub struct Cache {
+    dir: Utf8PathBuf,
+    max_age: TimeDelta,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct CacheEntry {
+    pub(crate) spreadsheet_id: String,
+    pub(crate) range: String,
+    pub(crate) fetched_at: DateTime<Utc>,
+    pub(crate) fetched_by: String,
+    pub(crate) content_hash: String,
+    data: serde_json::Value,
+}
+
+impl CacheEntry {
+    fn new(spreadsheet_id: &str, range: &str, data: serde_json::Value) -> anyhow::Result<Self> {
+        Ok(Self {
+            spreadsheet_id: spreadsheet_id.to_string(),
+            range: range.to_string(),
+            fetched_at: Utc::now(),
+            fetched_by: std::env::var("USER").unwrap_or_else(|_| "unknown".to_string()),
+            content_hash: content_hash(&data)?,
+            data,
+        })
+    }
+
+    pub(crate) fn age(&self) -> TimeDelta {
+        Utc::now() - self.fetched_at
+    }
+
+    pub(crate) fn is_intact(&self) -> bool {
+        content_hash(&self.data).is_ok_and(|h| h == self.content_hash)
+    }
+}
+
+impl Cache {
+    pub fn new(dir: &Utf8Path, config: &Config) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            max_age: TimeDelta::minutes(config.max_age_minutes.into()),
+        }
+    }
+
+    /// Return the cached contents of `range` if they are fresh and intact, otherwise download them and update the
+    /// cache
+    pub(crate) async fn get_or_download<T: DeserializeOwned>(
+        &self,
+        client: &GoogleSheetsClient,
+        spreadsheet_id: &str,
+        range: &str,
+    ) -> anyhow::Result<T> {
+        let path = self.entry_path(spreadsheet_id, range);
+
+        match read_entry(&path) {
+            Ok(entry) if self.is_fresh(&entry) => serde_json::from_value(entry.data)
+                .context(format!("failed to deserialize cached spreadsheet at {path}")),
+            _ => self.download(client, spreadsheet_id, range).await,
+        }
+    }
+
+    /// Treat every cached entry as stale, forcing the next access to download it again
+    pub(crate) fn force_refresh(&self) -> Self {
+        Self {
+            max_age: TimeDelta::zero(),
+            ..self.clone()
+        }
+    }
+
+    async fn download<T: DeserializeOwned>(
+        &self,
+        client: &GoogleSheetsClient,
+        spreadsheet_id: &str,
+        range: &str,
+    ) -> anyhow::Result<T> {
+        let data: serde_json::Value = client.download_range(spreadsheet_id, range).await?;
+
+        // Only cache what can actually be used, so that a bad response is downloaded again next time rather than
+        // being served from the cache until it goes stale
+        let deserialized = serde_json::from_value(data.clone()).context(format!(
+            "failed to deserialize spreadsheet {spreadsheet_id}, range {range}"
+        ))?;
+
+        let entry = CacheEntry::new(spreadsheet_id, range, data)?;
+        let path = self.entry_path(spreadsheet_id, range);
+        write_entry(&path, &entry).context(format!("failed to write cache entry {path}"))?;
+
+        Ok(deserialized)
+    }
+
+    pub(crate) fn is_fresh(&self, entry: &CacheEntry) -> bool {
+        entry.age() < self.max_age && entry.is_intact()
+    }
+
+    /// Every entry in the cache, along with the error from reading it if it couldn't be read (for example, if it was
+    /// truncated). A spreadsheet's directory that can't be read is reported as one such entry
+    pub(crate) fn entries(&self) -> anyhow::Result<Vec<(Utf8PathBuf, anyhow::Result<CacheEntry>)>> {
+        let root = self.dir.join(GOOGLE_SHEETS_SUBDIR);
+        if !root.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut entries = Vec::new();
+        for spreadsheet_dir in root.read_dir_utf8().context(format!("failed to read {root}"))? {
+            let spreadsheet_dir = spreadsheet_dir?;
+
+            // Anything else (like a `.DS_Store`) wasn't put there by the cache
+            if !spreadsheet_dir.file_type()?.is_dir() {
+                continue;
+            }
+
+            let spreadsheet_dir = spreadsheet_dir.into_path();
+            match spreadsheet_entry_paths(&spreadsheet_dir) {
+                Ok(paths) => entries.extend(paths.into_iter().map(|p| {
+                    let entry = read_entry(&p);
+                    (p, entry)
+                })),
+                Err(e) => entries.push((spreadsheet_dir, Err(e))),
+            }
+        }
+
+        entries.sort_by(|(p1, _), (p2, _)| p1.cmp(p2));
+
+        Ok(entries)
+    }
+
+    pub(crate) fn clear(&self) -> anyhow::Result<usize> {
+        let entries = self.entries()?;
+
+        let root = self.dir.join(GOOGLE_SHEETS_SUBDIR);
+        if root.exists() {
+            fs::remove_dir_all(&root).context(format!("failed to remove {root}"))?;
+        }
+
+        Ok(entries.len())
+    }
+
+    pub(crate) fn dir(&self) -> &Utf8Path {
+        &self.dir
+    }
+
+    pub(crate) fn max_age(&self) -> TimeDelta {
+        self.max_age
+    }
+
+    fn entry_path(&self, spreadsheet_id: &str, range: &str) -> Utf8PathBuf {
+        let filename: String = range
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+            .collect();
+
+        self.dir
+            .join(GOOGLE_SHEETS_SUBDIR)
+            .join(spreadsheet_id)
+            .join(format!("{filename}.json"))
+    }
+}
+
+/// The paths of the entries cached for one spreadsheet
+fn spreadsheet_entry_paths(spreadsheet_dir: &Utf8Path) -> anyhow::Result<Vec<Utf8PathBuf>> {
+    let mut paths = Vec::new();
+    for path in spreadsheet_dir
+        .read_dir_utf8()
+        .context(format!("failed to read {spreadsheet_dir}"))?
+    {
+        let path = path.context(format!("failed to read {spreadsheet_dir}"))?.into_path();
+        if path.extension() == Some("json") {
+            paths.push(path);
+        }
+    }
+
+    Ok(paths)
+}
+
+fn read_entry(path: &Utf8Path) -> anyhow::Result<CacheEntry> {
+    let contents = fs::read_to_string(path).context(format!("failed to read cache entry {path}"))?;
+
+    serde_json::from_str(&contents).context(format!("failed to deserialize cache entry {path}"))
+}
+
+fn write_entry(path: &Utf8Path, entry: &CacheEntry) -> anyhow::Result<()> {
+    let parent = path
+        .parent()
+        .ok_or(anyhow!("failed to get parent directory of {path}"))?;
+    fs::create_dir_all(parent)?;
+
+    // Write to a temporary file first so that a concurrent reader never sees a half-written entry
+    let tmp_path = path.with_extension("json.tmp");
+    fs::write(&tmp_path, serde_json::to_string_pretty(entry)?)?;
+    fs::rename(&tmp_path, path)?;
+
+    Ok(())
+}
+
+fn content_hash(data: &serde_json::Value) -> anyhow::Result<String> {
+    let serialized = serde_json::to_vec(data)?;
+    let hash = Sha256::digest(serialized);
+
+    Ok(hash.iter().map(|b| format!("{b:02x}")).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn lists_entries_despite_stray_and_corrupted_files() {
+        let dir = TempDir::new().unwrap();
+        let cache_dir = Utf8PathBuf::from_path_buf(dir.path().to_path_buf()).unwrap();
+        let cache = Cache::new(&cache_dir, &Config::default());
+
+        let intact_path = cache.entry_path("spreadsheet", "Sheet1!A1:B2");
+        let entry = CacheEntry::new(
+            "spreadsheet",
+            "Sheet1!A1:B2",
+            serde_json::json!({"values": [["a", "b"]]}),
+        )
+        .unwrap();
+        write_entry(&intact_path, &entry).unwrap();
+
+        let corrupted_path = cache.entry_path("spreadsheet", "Sheet2!A1:B2");
+        fs::write(&corrupted_path, "{\"spreadsheet_id\": ").unwrap();
+
+        let root = cache_dir.join(GOOGLE_SHEETS_SUBDIR);
+        fs::write(root.join(".DS_Store"), "").unwrap();
+        fs::write(root.join("spreadsheet/.DS_Store"), "").unwrap();
+
+        let entries = cache.entries().unwrap();
+        let entries = entries
+            .iter()
+            .map(|(path, entry)| (path, entry.as_ref().map(|e| e.range.as_str()).ok()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(entries, [(&intact_path, Some("Sheet1!A1:B2")), (&corrupted_path, None)]);
+        assert_eq!(cache.clear().unwrap(), 2);
+        assert!(!root.exists());
+    }
+}
@@ -1,15 +1,25 @@
 use std::fs;
 
+pub use cache::Cache;
 use camino::{Utf8Path, Utf8PathBuf};
+pub use samplesheet::{SamplesheetOutputs, format::SamplesheetFormat};
 use serde::Deserialize;
+pub use xenium::{StagingOptions, plan::CollisionPolicy};
 
+mod cache;
+mod problems;
 mod samplesheet;
+mod summary;
 mod xenium;
 
+const TRACKING_SHEET_SUBDIR: &str = "chromium-tracking-sheet";
+
 #[derive(Deserialize)]
 pub struct AppConfig {
     pub samplesheet: samplesheet::config::Config,
     pub xenium: xenium::config::Config,
+    #[serde(default)]
+    pub cache: cache::Config,
 }
 
 impl AppConfig {
@@ -20,17 +30,109 @@ impl AppConfig {
 
 pub async fn stage_xenium_data(
     config: &xenium::config::Config,
+    cache: &Cache,
     data_dirs: &[Utf8PathBuf],
-    skip_confirm: bool,
+    options: &StagingOptions<'_>,
 ) -> anyhow::Result<()> {
-    xenium::stage_data(config, data_dirs, skip_confirm).await
+    xenium::stage_data(config, cache, data_dirs, options).await
+}
+
+pub fn verify_xenium_delivery(slide_dirs: &[Utf8PathBuf]) -> anyhow::Result<()> {
+    xenium::verify(slide_dirs)
 }
 
-pub fn write_samplesheet(
+pub async fn write_samplesheet(
     config: &samplesheet::config::Config,
+    cache: &Cache,
     fastq_paths: &[Utf8PathBuf],
-    tracking_sheet_dir: &Utf8Path,
-    output_path: &Utf8Path,
+    fetch_tracking_sheet: bool,
+    outputs: &SamplesheetOutputs<'_>,
+) -> anyhow::Result<()> {
+    let tracking_sheet_dir = cache.dir().join(TRACKING_SHEET_SUBDIR);
+    let tracking_sheet =
+        samplesheet::load_tracking_sheet(config, cache, &tracking_sheet_dir, fetch_tracking_sheet).await?;
+
+    samplesheet::write(config, tracking_sheet, fastq_paths, outputs)
+}
+
+pub async fn summarize_metrics(
+    config: &samplesheet::config::Config,
+    cache: &Cache,
+    nf_tenx_dirs: &[Utf8PathBuf],
+    fetch_tracking_sheet: bool,
+    output_dir: &Utf8Path,
+) -> anyhow::Result<()> {
+    let tracking_sheet_dir = cache.dir().join(TRACKING_SHEET_SUBDIR);
+
+    summary::write(
+        config,
+        cache,
+        nf_tenx_dirs,
+        &tracking_sheet_dir,
+        fetch_tracking_sheet,
+        output_dir,
+    )
+    .await
+}
+
+pub fn print_cache_status(cache: &Cache) -> anyhow::Result<()> {
+    let entries = cache.entries()?;
+    if entries.is_empty() {
+        println!("no cached spreadsheets in {}", cache.dir());
+        return Ok(());
+    }
+
+    for (path, entry) in &entries {
+        println!("{path}");
+
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                println!("  error:        {err:#}");
+                println!("  status:       corrupted");
+                continue;
+            }
+        };
+
+        let status = if !entry.is_intact() {
+            "corrupted"
+        } else if cache.is_fresh(entry) {
+            "fresh"
+        } else {
+            "stale"
+        };
+
+        println!("  spreadsheet:  {}", entry.spreadsheet_id);
+        println!("  range:        {}", entry.range);
+        println!("  fetched at:   {} by {}", entry.fetched_at, entry.fetched_by);
+        println!(
+            "  age:          {} minutes (max {} minutes)",
+            entry.age().num_minutes(),
+            cache.max_age().num_minutes()
+        );
+        println!("  content hash: {}", entry.content_hash);
+        println!("  status:       {status}");
+    }
+
+    Ok(())
+}
+
+pub async fn refresh_cache(
+    samplesheet_config: &samplesheet::config::Config,
+    xenium_config: &xenium::config::Config,
+    cache: &Cache,
 ) -> anyhow::Result<()> {
-    samplesheet::write(config, fastq_paths, tracking_sheet_dir, output_path)
+    let cache = cache.force_refresh();
+
+    samplesheet::refresh_cache(samplesheet_config, &cache).await?;
+    xenium::refresh_cache(xenium_config, &cache).await?;
+
+    Ok(())
+}
+
+pub fn clear_cache(cache: &Cache) -> anyhow::Result<()> {
+    let n_entries = cache.clear()?;
+    println!("removed {n_entries} cached spreadsheets from {}", cache.dir());
+
+    Ok(())
 }
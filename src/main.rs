@@ -3,7 +3,10 @@ use std::str::FromStr;
 use anyhow::Context;
 use camino::Utf8PathBuf;
 use clap::{Parser, Subcommand};
-use scbl_utils::{AppConfig, stage_xenium_data, write_samplesheet};
+use scbl_utils::{
+    AppConfig, Cache, CollisionPolicy, SamplesheetFormat, SamplesheetOutputs, StagingOptions, clear_cache,
+    print_cache_status, refresh_cache, stage_xenium_data, summarize_metrics, verify_xenium_delivery, write_samplesheet,
+};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -14,20 +17,74 @@ async fn main() -> anyhow::Result<()> {
         command,
     } = Cli::parse();
 
-    let AppConfig { samplesheet, xenium } =
-        AppConfig::read_toml_file(&config_path).context("failed to read scbl-utils configuration")?;
+    let AppConfig {
+        samplesheet,
+        xenium,
+        cache: cache_config,
+    } = AppConfig::read_toml_file(&config_path).context("failed to read scbl-utils configuration")?;
+    let cache = Cache::new(&cache_dir, &cache_config);
 
     match command {
         Command::Samplesheet {
             fastq_paths,
             output_path,
+            format,
+            report_path,
+            fetch_tracking_sheet,
+            multi_config_dir,
+            arc_libraries_dir,
+            feature_reference_dir,
         } => {
-            let tracking_sheet_dir = cache_dir.join("chromium-tracking-sheet");
-            write_samplesheet(&samplesheet, &fastq_paths, &tracking_sheet_dir, &output_path)?
+            let outputs = SamplesheetOutputs {
+                output_path: &output_path,
+                format,
+                report_path: report_path.as_deref(),
+                multi_config_dir: multi_config_dir.as_deref(),
+                arc_libraries_dir: arc_libraries_dir.as_deref(),
+                feature_reference_dir: &feature_reference_dir,
+            };
+
+            write_samplesheet(&samplesheet, &cache, &fastq_paths, fetch_tracking_sheet, &outputs).await?
+        }
+        Command::StageXenium {
+            data_dirs,
+            yes,
+            dry_run,
+            plan_path,
+            on_collision,
+            jobs,
+            reruns,
+            spreadsheet_file,
+        } => {
+            let options = StagingOptions {
+                skip_confirm: yes,
+                dry_run,
+                plan_path: plan_path.as_deref(),
+                collision_policy: on_collision,
+                max_parallel_moves: jobs,
+                reruns,
+                spreadsheet_file: spreadsheet_file.as_deref(),
+            };
+
+            stage_xenium_data(&xenium, &cache, &data_dirs, &options)
+                .await
+                .context("failed to stage xenium data directories")?
         }
-        Command::StageXenium { data_dirs, yes } => stage_xenium_data(&xenium, &data_dirs, yes)
+        Command::Verify { slide_dirs } => verify_xenium_delivery(&slide_dirs)?,
+        Command::Summarize {
+            nf_tenx_dirs,
+            output_dir,
+            fetch_tracking_sheet,
+        } => summarize_metrics(&samplesheet, &cache, &nf_tenx_dirs, fetch_tracking_sheet, &output_dir)
             .await
-            .context("failed to stage xenium data directories")?,
+            .context("failed to summarize nf-tenx metrics")?,
+        Command::Cache { command } => match command {
+            CacheCommand::Status => print_cache_status(&cache)?,
+            CacheCommand::Refresh => refresh_cache(&samplesheet, &xenium, &cache)
+                .await
+                .context("failed to refresh cache")?,
+            CacheCommand::Clear => clear_cache(&cache)?,
+        },
     }
 
     Ok(())
@@ -43,15 +100,94 @@ enum Command {
         /// The path at which to write the resulting samplesheet
         #[arg(short, long, default_value_t = Utf8PathBuf::from_str("samplesheet.yaml").unwrap())]
         output_path: Utf8PathBuf,
+        /// The format in which to write the samplesheet. If not given, it is inferred from the extension of
+        /// `--output-path`
+        #[arg(long, value_enum)]
+        format: Option<SamplesheetFormat>,
+        /// Also write the libraries that couldn't be reconciled between the FASTQ files and the tracking sheet to this
+        /// path as JSON. They are always printed to stderr
+        #[arg(long)]
+        report_path: Option<Utf8PathBuf>,
+        /// Download the Chromium tracking sheet directly from Google Sheets instead of reading CSV files from the
+        /// cache directory. Requires `samplesheet.tracking_sheet_spec` to be set in the configuration file
+        #[arg(short, long, default_value_t)]
+        fetch_tracking_sheet: bool,
+        /// Also write a cellranger multi config CSV (`<GEMS ID>.csv`) to this directory for each GEMs whose chemistry
+        /// uses `cellranger multi`
+        #[arg(long)]
+        multi_config_dir: Option<Utf8PathBuf>,
+        /// Also write a cellranger-arc libraries CSV (`<GEMS ID>.csv`) to this directory for each GEMs whose chemistry
+        /// uses `cellranger-arc`
+        #[arg(long)]
+        arc_libraries_dir: Option<Utf8PathBuf>,
+        /// The directory in which to write a cellranger feature reference (`<GEMS ID>/feature_ref.csv`) for each GEMs
+        /// with Antibody Capture or CRISPR Guide Capture libraries, built from the tracking sheet's Feature Panels
+        #[arg(long, default_value_t = Utf8PathBuf::from_str("feature-references").unwrap())]
+        feature_reference_dir: Utf8PathBuf,
     },
     /// Move the outputs of a Xenium run into the staging directory for delivery
     StageXenium {
-        /// The data directories produced by the instrument
+        /// The data directories produced by the instrument, or by xeniumranger if `--reruns` is passed
         data_dirs: Vec<Utf8PathBuf>,
         /// Move the files without confirmation (useful for batch jobs or scripts)
         #[arg(short, long, default_value_t)]
         yes: bool,
+        /// Print every planned move, including its lab staging directory and the `design` directory that would be
+        /// created, without moving anything
+        #[arg(long, default_value_t)]
+        dry_run: bool,
+        /// Also write the planned moves to this path as JSON
+        #[arg(long)]
+        plan_path: Option<Utf8PathBuf>,
+        /// What to do when a slide's destination already exists in the staging directory
+        #[arg(long, value_enum, default_value_t)]
+        on_collision: CollisionPolicy,
+        /// The maximum number of slides to move at once
+        #[arg(short, long, default_value_t = 4)]
+        jobs: usize,
+        /// The data directories are the outputs of `xeniumranger resegment`, `relabel`, or `import-segmentation`,
+        /// which are delivered next to the slides they were run on under `xeniumranger-<COMMAND>/v<N>_<OUTPUT ID>`
+        #[arg(long, default_value_t)]
+        reruns: bool,
+        /// Read the slide spreadsheet from this CSV, XLSX, or JSON file instead of downloading it from Google Sheets.
+        /// CSV and XLSX files are cropped to `xenium.spreadsheet_spec.range`, while a JSON file must be a Google Sheets
+        /// value range, such as one from the cache directory
+        #[arg(long)]
+        spreadsheet_file: Option<Utf8PathBuf>,
+    },
+    /// Check staged Xenium slide directories against the `manifest.sha256` written when they were staged
+    Verify {
+        /// The slide directories (`<SLIDE ID>-<RUN ID>_<SLIDE NAME>`) to check
+        slide_dirs: Vec<Utf8PathBuf>,
     },
+    /// Combine the cellranger metrics from nf-tenx outputs into one CSV per lab, joined with the Chromium tracking
+    /// sheet
+    Summarize {
+        /// The nf-tenx output directories to search for `metrics_summary.csv` files
+        nf_tenx_dirs: Vec<Utf8PathBuf>,
+        /// The directory in which to write the summary CSVs
+        #[arg(short, long, default_value_t = Utf8PathBuf::from_str("metrics-summary").unwrap())]
+        output_dir: Utf8PathBuf,
+        /// Download the Chromium tracking sheet directly from Google Sheets instead of reading CSV files from the
+        /// cache directory. Requires `samplesheet.tracking_sheet_spec` to be set in the configuration file
+        #[arg(short, long, default_value_t)]
+        fetch_tracking_sheet: bool,
+    },
+    /// Inspect and manage the spreadsheets downloaded into the cache directory
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum CacheCommand {
+    /// Show when and by whom each cached spreadsheet was downloaded, and whether it is still fresh
+    Status,
+    /// Download every configured spreadsheet again, regardless of how fresh the cached copy is
+    Refresh,
+    /// Remove every cached spreadsheet
+    Clear,
 }
 
 /// A command-line utility for data-processing and delivery at the Single Cell Biology Laboratory at the Jackson
@@ -0,0 +1,48 @@
+use std::fmt::Display;
+
+use anyhow::bail;
+use itertools::Itertools;
+
+// Checks of the tracking sheet, the FASTQ files, and Xenium deliveries can each turn up many problems at once. Whoever
+// has to fix them should be able to do so in one pass rather than rerunning after each fix, so these checks collect
+// every problem they find and only then fail, listing all of them.
+
+/// Fail with every one of `problems` with `what`, unless there are none
+pub(crate) fn bail_on_problems(what: impl Display, problems: &[String]) -> anyhow::Result<()> {
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    let n_problems = problems.len();
+    let noun = if n_problems == 1 { "problem" } else { "problems" };
+
+    bail!(
+        "found {n_problems} {noun} with {what}:\n{}",
+        problems.iter().map(|p| format!("  - {p}")).join("\n")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn lists_every_problem() {
+        assert!(bail_on_problems("nothing", &[]).is_ok());
+
+        let err = bail_on_problems("the FASTQ files", &["a.fastq.gz is empty".to_string()]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "found 1 problem with the FASTQ files:\n  - a.fastq.gz is empty"
+        );
+
+        let problems = ["a is missing".to_string(), "b is missing".to_string()];
+        let err = bail_on_problems("the slide", &problems).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "found 2 problems with the slide:\n  - a is missing\n  - b is missing"
+        );
+    }
+}
@@ -1,37 +1,101 @@
-use std::{collections::HashMap, fs};
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs, iter,
+};
 
 use anyhow::{Context, anyhow, ensure};
 use camino::{Utf8Path, Utf8PathBuf};
+use domain::{CellularMaterial, Chemistry, LibraryType, Species};
+use fastq::{FastqFile, ReadType};
+use feature_reference::FeaturePanels;
+use format::SamplesheetFormat;
 use itertools::Itertools;
+use reconciliation::ReconciliationReport;
 use serde::Serialize;
-use tracking_sheet::{FromTrackingSheetDir, Gems, GemsSuspensions, Id, Library, MultiplexedSuspension, Suspension};
+use tag::SampleBarcode;
+use tracking_sheet::{Id, Library, MultiplexedSuspension, Suspension, TrackingSheet};
+
+use crate::{cache::Cache, problems::bail_on_problems, xenium::client::GoogleSheetsClient};
+mod arc_libraries;
 pub(super) mod config;
-mod tracking_sheet;
+pub(super) mod domain;
+mod fastq;
+mod feature_reference;
+pub(super) mod format;
+mod multi_config;
+mod reconciliation;
+mod tag;
+pub(super) mod tracking_sheet;
+
+/// Where `samplesheet` should write its outputs
+pub struct SamplesheetOutputs<'a> {
+    pub output_path: &'a Utf8Path,
+    /// Inferred from the extension of `output_path` if not given
+    pub format: Option<SamplesheetFormat>,
+    /// Where to write the libraries that couldn't be reconciled as JSON
+    pub report_path: Option<&'a Utf8Path>,
+    /// The directory in which to write a cellranger multi config for each GEMs that uses `cellranger multi`
+    pub multi_config_dir: Option<&'a Utf8Path>,
+    /// The directory in which to write a libraries CSV for each GEMs that uses `cellranger-arc`
+    pub arc_libraries_dir: Option<&'a Utf8Path>,
+    /// The directory in which to write the feature reference of each GEMs with feature barcoding libraries
+    pub feature_reference_dir: &'a Utf8Path,
+}
 
 pub(super) fn write(
     config: &config::Config,
+    tracking_sheet: TrackingSheet,
     fastq_paths: &[Utf8PathBuf],
-    tracking_sheet_dir: &Utf8Path,
-    output_path: &Utf8Path,
+    outputs: &SamplesheetOutputs,
 ) -> anyhow::Result<()> {
+    let SamplesheetOutputs {
+        output_path,
+        format,
+        report_path,
+        multi_config_dir,
+        arc_libraries_dir,
+        feature_reference_dir,
+    } = *outputs;
+
+    let format = match format {
+        Some(format) => format,
+        None => SamplesheetFormat::from_path(output_path)?,
+    };
+
+    // Catch incomplete or malformed deliveries here rather than letting nf-tenx fail on them later
+    fastq::validate(fastq_paths).context("FASTQ validation failed, refusing to write samplesheet")?;
+
+    let read_types_by_library = fastq::read_types_by_library(fastq_paths);
     let fastq_paths =
-        library_id_to_fastq_dir(fastq_paths).context("failed to determine library IDs from FASTQ paths")?;
+        library_id_to_fastq_dirs(fastq_paths).context("failed to determine library IDs from FASTQ paths")?;
+
+    let TrackingSheet {
+        suspensions,
+        multiplexed_suspensions,
+        gems,
+        gems_suspensions,
+        libraries,
+        features,
+    } = tracking_sheet;
+
+    let feature_panels = FeaturePanels::new(&features)?;
+
+    let report = ReconciliationReport::new(&libraries, &fastq_paths);
+    report.print();
+    if let Some(report_path) = report_path {
+        report.write_json(report_path)?;
+    }
 
-    let suspensions = Suspension::from_tracking_sheet_dir(tracking_sheet_dir)?;
     let suspensions_grouped_by_pool = suspensions
         .iter()
         .filter_map(|s| s.pooled_into_id.as_ref().map(|m| (m.as_str(), s)))
         .into_group_map();
     let suspensions = map_entity_id_to_entity(&suspensions);
 
-    let multiplexed_suspensions = MultiplexedSuspension::from_tracking_sheet_dir(tracking_sheet_dir)?;
     let multiplexed_suspensions = map_entity_id_to_entity(&multiplexed_suspensions);
 
-    let gems = Gems::from_tracking_sheet_dir(tracking_sheet_dir)?;
     let gems = map_entity_id_to_entity(&gems);
 
-    let gems_suspensions = GemsSuspensions::from_tracking_sheet_dir(tracking_sheet_dir)?;
-
     let suspension_ids_grouped_by_gems_id = gems_suspensions
         .iter()
         .filter_map(|gs| gs.suspension_id.as_ref().map(|s| (gs.gems_id.as_str(), s.as_str())))
@@ -46,12 +110,15 @@ pub(super) fn write(
         })
         .into_group_map();
 
-    let libraries = Library::from_tracking_sheet_dir(tracking_sheet_dir)?;
     let libraries_grouped_by_gems_id = libraries
         .iter()
-        .filter_map(|l| fastq_paths.get(l.id()).map(|p| (l.gems_id.as_str(), (l, *p))))
+        .filter_map(|l| fastq_paths.get(l.id()).map(|p| (l.gems_id.as_str(), (l, p))))
         .into_group_map();
 
+    for dir in [multi_config_dir, arc_libraries_dir].into_iter().flatten() {
+        fs::create_dir_all(dir).context(format!("failed to create directory {dir}"))?;
+    }
+
     let mut samplesheets = Vec::new();
 
     for (gems_id, libs) in &libraries_grouped_by_gems_id {
@@ -61,17 +128,12 @@ pub(super) fn write(
 
         let mut original_types = Vec::new();
 
-        for (Library { id, type_, .. }, fastq_dir) in libs {
+        for (Library { id, type_, .. }, fastq_dirs) in libs {
             library_ids.push(id.as_str());
-            library_fastqs.push(*fastq_dir);
+            library_fastqs.push(fastq_dirs.as_slice());
 
-            let mapped_type_ = match type_.as_str() {
-                "Gene Expression Flex" => "Gene Expression",
-                _ => type_,
-            };
-
-            library_types.push(mapped_type_);
-            original_types.push(type_.as_str());
+            library_types.push(type_.cellranger_name());
+            original_types.push(*type_);
         }
 
         let library_gems = gems.get(gems_id).ok_or(anyhow!("GEMs ID {gems_id} not found"))?;
@@ -80,6 +142,11 @@ pub(super) fn write(
             .get(&library_gems.chemistry)
             .ok_or(anyhow!("chemistry {} not found in config", library_gems.chemistry))?;
 
+        let is_arc = tool == ARC_TOOL;
+        if is_arc {
+            ensure_arc_libraries(gems_id, libs, &read_types_by_library)?;
+        }
+
         let sample = Sample::from_entities(
             gems_id,
             &suspension_ids_grouped_by_gems_id,
@@ -89,11 +156,14 @@ pub(super) fn write(
             &multiplexed_suspensions,
         )?;
 
-        let design = sample.design().context("failed to create 'design' field")?;
+        let sample_barcode = sample.sample_barcode(library_gems.chemistry, &original_types);
+        let design = sample
+            .design(sample_barcode)
+            .context("failed to create 'design' field")?;
 
         let species = sample.species();
 
-        let reference_paths = config.species_reference_path.get(species).ok_or(anyhow!(
+        let reference_paths = config.species_reference_path.get(&species).ok_or(anyhow!(
             "species {species} not found in config's 'species_reference_path'"
         ))?;
         let reference_path = reference_paths.get(&format!("{tool} {command}")).ok_or(anyhow!(
@@ -101,15 +171,59 @@ pub(super) fn write(
             library_gems.chemistry
         ))?;
 
-        let probe_set = if original_types.contains(&"Gene Expression Flex") {
-            Some(config.species_probe_set.get(species).ok_or(anyhow!(
+        let probe_set = if original_types.contains(&LibraryType::GeneExpressionFlex) {
+            Some(config.species_probe_set.get(&species).ok_or(anyhow!(
                 "species {species} not found in config's 'species_reference_probe_set'"
             ))?)
         } else {
             None
         };
 
-        let is_nuclei = sample.is_nuclei()?;
+        let feature_types = original_types
+            .iter()
+            .copied()
+            .filter(LibraryType::is_feature_barcoding)
+            .unique()
+            .collect_vec();
+        let feature_reference = if feature_types.is_empty() {
+            None
+        } else {
+            let features = feature_panels
+                .for_gems(gems_id, &sample.suspension_ids(), &feature_types)
+                .context(format!("failed to find the features for GEMs {gems_id}"))?;
+
+            // cellranger only recognizes a hashtag that's an Antibody Capture feature in the feature reference
+            if let (Some(SampleBarcode::Hashtag), Some(design)) = (sample_barcode, &design) {
+                let unknown = design
+                    .keys()
+                    .filter(|tag| {
+                        !features
+                            .iter()
+                            .any(|f| f.id == **tag && f.feature_type == LibraryType::AntibodyCapture)
+                    })
+                    .sorted()
+                    .join(", ");
+                ensure!(
+                    unknown.is_empty(),
+                    "GEMs {gems_id} is hashed with tags that aren't Antibody Capture features in the feature panels: \
+                     {unknown}"
+                );
+            }
+
+            Some(feature_reference::write(feature_reference_dir, gems_id, &features)?)
+        };
+
+        let has_vdj = original_types.iter().any(LibraryType::is_vdj);
+        let vdj_reference = if has_vdj {
+            Some(config.species_vdj_reference_path.get(&species).ok_or(anyhow!(
+                "species {species} not found in config's 'species_vdj_reference_path'"
+            ))?)
+        } else {
+            None
+        };
+        let inner_enrichment_primers = library_gems.inner_enrichment_primers.as_ref().filter(|_| has_vdj);
+
+        let is_nuclei = sample.is_nuclei();
 
         let samplesheet = Samplesheet {
             sample_name: sample.name(),
@@ -123,34 +237,134 @@ pub(super) fn write(
             command,
             reference_path,
             probe_set,
+            feature_reference,
+            vdj_reference,
+            inner_enrichment_primers,
         };
 
+        if let Some(dir) = multi_config_dir
+            && command == "multi"
+        {
+            multi_config::write(dir, gems_id, &samplesheet, sample_barcode)?;
+        }
+
+        if let Some(dir) = arc_libraries_dir
+            && is_arc
+        {
+            arc_libraries::write(dir, gems_id, &samplesheet)?;
+        }
+
         samplesheets.push(samplesheet);
     }
 
-    fs::write(output_path, serde_json::to_string_pretty(&samplesheets)?)
-        .context(format!("failed to write samplesheet to {output_path}"))?;
+    let serialized = format
+        .serialize(&samplesheets)
+        .context(format!("failed to serialize samplesheet as {format:?}"))?;
+
+    fs::write(output_path, serialized).context(format!("failed to write samplesheet to {output_path}"))?;
 
     Ok(())
 }
 
-fn library_id_to_fastq_dir(fastq_paths: &[Utf8PathBuf]) -> anyhow::Result<HashMap<&str, &Utf8Path>> {
-    let mut library_ids_to_fastqs = HashMap::new();
-    for p in fastq_paths {
-        let err = format!("malformed FASTQ path: {p}");
+const ARC_TOOL: &str = "cellranger-arc";
 
-        ensure!(!p.is_dir(), "fastq paths must point to files, but {p} is a directory");
-        ensure!(p.is_file(), "fastq paths must point to files, but {p} does not");
+/// `cellranger-arc` needs both a Gene Expression and a Chromatin Accessibility library, and the latter must have the
+/// R3 read that holds its genomic sequence
+fn ensure_arc_libraries(
+    gems_id: &str,
+    libraries: &[(&Library, &Vec<&Utf8Path>)],
+    read_types_by_library: &HashMap<&str, BTreeSet<ReadType>>,
+) -> anyhow::Result<()> {
+    for required in [LibraryType::GeneExpression, LibraryType::ChromatinAccessibility] {
+        ensure!(
+            libraries.iter().any(|(l, _)| l.type_ == required),
+            "GEMs {gems_id} uses {ARC_TOOL}, but has no {required} library"
+        );
+    }
+
+    for (Library { id, type_, .. }, _) in libraries {
+        if *type_ != LibraryType::ChromatinAccessibility {
+            continue;
+        }
 
-        let filename = p
-            .file_name()
-            .ok_or_else(|| anyhow!(err.clone()))?
-            .split('_')
-            .next()
-            .unwrap_or_default();
-        let dir = p.parent().ok_or_else(|| anyhow!(err))?;
+        let read_types = read_types_by_library.get(id.as_str()).cloned().unwrap_or_default();
+        for required in [ReadType::R1, ReadType::R2, ReadType::R3] {
+            ensure!(
+                read_types.contains(&required),
+                "library {id} is a {} library, so it needs R1, R2, and R3 FASTQ files, but it has no {required}",
+                LibraryType::ChromatinAccessibility
+            );
+        }
+    }
+
+    Ok(())
+}
 
-        library_ids_to_fastqs.insert(filename, dir);
+pub(super) async fn refresh_cache(config: &config::Config, cache: &Cache) -> anyhow::Result<()> {
+    if config.tracking_sheet_spec.is_some() {
+        download_tracking_sheet(config, cache).await?;
+    }
+
+    Ok(())
+}
+
+pub(super) async fn load_tracking_sheet(
+    config: &config::Config,
+    cache: &Cache,
+    tracking_sheet_dir: &Utf8Path,
+    fetch_tracking_sheet: bool,
+) -> anyhow::Result<TrackingSheet> {
+    if fetch_tracking_sheet {
+        download_tracking_sheet(config, cache).await
+    } else {
+        TrackingSheet::from_dir(tracking_sheet_dir, &config.aliases)
+            .context(format!("failed to read tracking sheet from {tracking_sheet_dir}"))
+    }
+}
+
+async fn download_tracking_sheet(config: &config::Config, cache: &Cache) -> anyhow::Result<TrackingSheet> {
+    let config::TrackingSheetSpecification {
+        id,
+        google_sheets_api_key,
+        google_sheets_api_url,
+    } = config.tracking_sheet_spec.as_ref().ok_or(anyhow!(
+        "'tracking_sheet_spec' must be set in config to download the tracking sheet"
+    ))?;
+
+    let client = match google_sheets_api_url {
+        Some(url) => GoogleSheetsClient::with_base_url(google_sheets_api_key, url),
+        None => GoogleSheetsClient::new(google_sheets_api_key),
+    }
+    .context("failed to create Google Sheets client")?;
+
+    TrackingSheet::download(cache, &client, id, &config.aliases)
+        .await
+        .context("failed to download tracking sheet")
+}
+
+// A library may be sequenced more than once (e.g. a top-up run), in which case its FASTQs are split across multiple
+// delivery directories, all of which must be kept
+fn library_id_to_fastq_dirs(fastq_paths: &[Utf8PathBuf]) -> anyhow::Result<HashMap<&str, Vec<&Utf8Path>>> {
+    let mut library_ids_to_fastqs: HashMap<&str, Vec<&Utf8Path>> = HashMap::new();
+    for p in fastq_paths.iter().filter(|p| fastq::is_fastq(p)) {
+        // The library ID is read the same way as during validation, so that an ID containing '_' is kept whole
+        let FastqFile { library_id, .. } = FastqFile::from_path(p)?;
+        let dir = p.parent().ok_or(anyhow!("malformed FASTQ path: {p}"))?;
+
+        let dirs = library_ids_to_fastqs.entry(library_id).or_default();
+        if !dirs.contains(&dir) {
+            dirs.push(dir);
+        }
+    }
+
+    for (library_id, dirs) in &library_ids_to_fastqs {
+        if dirs.len() > 1 {
+            eprintln!(
+                "library {library_id} was found in {} directories: {}",
+                dirs.len(),
+                dirs.iter().join(", ")
+            );
+        }
     }
 
     Ok(library_ids_to_fastqs)
@@ -194,7 +408,7 @@ impl<'a> Sample<'a> {
                             .map(|id| {
                                 suspensions
                                     .get(id)
-                                    .map(|s| *s)
+                                    .copied()
                                     .ok_or(anyhow!("suspension ID {id} not found"))
                             })
                             .try_collect()?,
@@ -208,7 +422,7 @@ impl<'a> Sample<'a> {
 
                 Ok(Sample::Multiplexed(
                     multiplexed_suspensions.get(ids[0]).ok_or(anyhow!(err.clone()))?,
-                    &suspensions_grouped_by_pool.get(ids[0]).ok_or(anyhow!(err))?,
+                    suspensions_grouped_by_pool.get(ids[0]).ok_or(anyhow!(err))?,
                 ))
             }
             (Some(_), Some(_)) => Err(anyhow!(
@@ -228,16 +442,26 @@ impl<'a> Sample<'a> {
         }
     }
 
-    fn design(&self) -> anyhow::Result<Option<HashMap<&'a str, SampleDesign<'a>>>> {
+    /// Map each sample's tag, normalized for `sample_barcode`, to its name and description, failing on any missing,
+    /// invalid, or duplicated tag in the pool
+    fn design(
+        &self,
+        sample_barcode: Option<SampleBarcode>,
+    ) -> anyhow::Result<Option<HashMap<String, SampleDesign<'a>>>> {
         let suspensions = match self {
-            Self::Singleplexed(_) => {
-                return Ok(None);
-            }
+            Self::Singleplexed(_) => return Ok(None),
             Self::Multiplexed(_, suspensions) => *suspensions,
             Self::Ocm(suspensions) => suspensions.as_slice(),
         };
+        // Only a singleplexed sample has no kind of tag
+        let Some(sample_barcode) = sample_barcode else {
+            return Ok(None);
+        };
 
+        let mut problems = Vec::new();
+        let mut suspension_ids_by_tag: HashMap<String, Vec<&str>> = HashMap::new();
         let mut design = HashMap::with_capacity(suspensions.len());
+
         for Suspension {
             id,
             tag_id,
@@ -247,11 +471,21 @@ impl<'a> Sample<'a> {
         } in suspensions
         {
             let Some(tag_id) = tag_id else {
-                return Err(anyhow!("no tag ID for suspension {id}"));
+                problems.push(format!("no tag ID for suspension {id}"));
+                continue;
+            };
+
+            let tag_id = match sample_barcode.normalize(tag_id) {
+                Ok(tag_id) => tag_id,
+                Err(err) => {
+                    problems.push(format!("suspension {id}: {err}"));
+                    continue;
+                }
             };
 
+            suspension_ids_by_tag.entry(tag_id.clone()).or_default().push(id);
             design.insert(
-                tag_id.as_str(),
+                tag_id,
                 SampleDesign {
                     name,
                     description: tissue,
@@ -259,31 +493,56 @@ impl<'a> Sample<'a> {
             );
         }
 
+        for (tag_id, suspension_ids) in suspension_ids_by_tag.iter().sorted() {
+            if suspension_ids.len() > 1 {
+                problems.push(format!(
+                    "tag {tag_id} is used by more than one suspension: {}",
+                    suspension_ids.iter().join(", ")
+                ));
+            }
+        }
+
+        bail_on_problems(format!("the tags of {}", self.name()), &problems)?;
+
         Ok(Some(design))
     }
 
-    fn species(&self) -> &'a str {
+    /// The kind of tag in the design, if there is one
+    fn sample_barcode(&self, chemistry: Chemistry, library_types: &[LibraryType]) -> Option<SampleBarcode> {
         match self {
-            Self::Singleplexed(Suspension { species, .. }) => species,
-            Self::Multiplexed(_, suspensions) => &suspensions[0].species,
-            Self::Ocm(suspensions) => &suspensions[0].species,
+            Self::Singleplexed(_) => None,
+            Self::Multiplexed(..) => Some(SampleBarcode::for_pool(chemistry, library_types)),
+            Self::Ocm(_) => Some(SampleBarcode::Ocm),
         }
     }
 
-    fn is_nuclei(&self) -> anyhow::Result<bool> {
-        let cellular_material = match self {
-            Self::Singleplexed(Suspension { cellular_material, .. }) => cellular_material.as_str(),
-            Self::Multiplexed(_, suspensions) => &suspensions[0].cellular_material,
-            Self::Ocm(suspensions) => &suspensions[0].cellular_material,
-        };
+    /// The suspensions whose features apply to the sample, including the pool itself for a multiplexed sample
+    fn suspension_ids(&self) -> Vec<&'a str> {
+        match self {
+            Self::Singleplexed(suspension) => vec![suspension.id()],
+            Self::Multiplexed(pool, suspensions) => iter::once(pool.id())
+                .chain(suspensions.iter().map(|s| s.id()))
+                .collect(),
+            Self::Ocm(suspensions) => suspensions.iter().map(|s| s.id()).collect(),
+        }
+    }
 
-        let is_nuclei = match cellular_material {
-            "Cells" => false,
-            "Nuclei" => true,
-            _ => return Err(anyhow!("unrecognized cellular material {cellular_material}")),
+    fn species(&self) -> Species {
+        match self {
+            Self::Singleplexed(Suspension { species, .. }) => *species,
+            Self::Multiplexed(_, suspensions) => suspensions[0].species,
+            Self::Ocm(suspensions) => suspensions[0].species,
+        }
+    }
+
+    fn is_nuclei(&self) -> bool {
+        let cellular_material = match self {
+            Self::Singleplexed(Suspension { cellular_material, .. }) => *cellular_material,
+            Self::Multiplexed(_, suspensions) => suspensions[0].cellular_material,
+            Self::Ocm(suspensions) => suspensions[0].cellular_material,
         };
 
-        Ok(is_nuclei)
+        cellular_material == CellularMaterial::Nuclei
     }
 }
 
@@ -298,8 +557,11 @@ pub struct Samplesheet<'a> {
     command: &'a str,
     reference_path: &'a Utf8Path,
     probe_set: Option<&'a Utf8PathBuf>,
-    design: Option<HashMap<&'a str, SampleDesign<'a>>>,
-    fastq_paths: Vec<&'a Utf8Path>,
+    feature_reference: Option<Utf8PathBuf>,
+    vdj_reference: Option<&'a Utf8PathBuf>,
+    inner_enrichment_primers: Option<&'a Utf8PathBuf>,
+    design: Option<HashMap<String, SampleDesign<'a>>>,
+    fastq_paths: Vec<&'a [&'a Utf8Path]>,
 }
 
 #[derive(Serialize, Clone)]
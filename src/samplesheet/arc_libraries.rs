@@ -0,0 +1,39 @@
+use std::fs;
+
+use anyhow::Context;
+use camino::Utf8Path;
+use itertools::Itertools;
+
+use super::Samplesheet;
+
+// `cellranger-arc count` takes a libraries CSV rather than a multi config:
+// fastqs,sample,library_type
+// <FASTQ DIR>,<LIBRARY ID>,Gene Expression
+// <FASTQ DIR>,<LIBRARY ID>,Chromatin Accessibility
+//
+// where `sample` is the prefix of the library's FASTQ files, which is its library ID.
+
+/// Write the libraries CSV for `samplesheet` to `<dir>/<GEMS ID>.csv`
+pub(super) fn write(dir: &Utf8Path, gems_id: &str, samplesheet: &Samplesheet) -> anyhow::Result<()> {
+    let Samplesheet {
+        libraries,
+        library_types,
+        fastq_paths,
+        ..
+    } = samplesheet;
+
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(["fastqs", "sample", "library_type"])?;
+
+    for ((library_id, library_type), dirs) in libraries.iter().zip_eq(library_types).zip_eq(fastq_paths) {
+        for dir in dirs.iter() {
+            let dir = dir
+                .canonicalize_utf8()
+                .context(format!("failed to get absolute path for {dir}"))?;
+            writer.write_record([dir.as_str(), library_id, library_type])?;
+        }
+    }
+
+    let path = dir.join(format!("{gems_id}.csv"));
+    fs::write(&path, writer.into_inner()?).context(format!("failed to write cellranger-arc libraries to {path}"))
+}
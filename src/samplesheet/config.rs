@@ -3,9 +3,30 @@ use std::collections::HashMap;
 use camino::Utf8PathBuf;
 use serde::Deserialize;
 
+use super::{
+    domain::{Chemistry, Species},
+    tracking_sheet::Aliases,
+};
+
 #[derive(Deserialize)]
 pub struct Config {
-    pub(super) species_reference_path: HashMap<String, HashMap<String, Utf8PathBuf>>,
-    pub(super) chemistry_program: HashMap<String, (String, String, String)>,
-    pub(super) species_probe_set: HashMap<String, Utf8PathBuf>,
+    pub(super) species_reference_path: HashMap<Species, HashMap<String, Utf8PathBuf>>,
+    pub(super) chemistry_program: HashMap<Chemistry, (String, String, String)>,
+    pub(super) species_probe_set: HashMap<Species, Utf8PathBuf>,
+    /// VDJ references are separate from the gene expression references, as both are needed for 5' immune profiling
+    #[serde(default)]
+    pub(super) species_vdj_reference_path: HashMap<Species, Utf8PathBuf>,
+    #[serde(default)]
+    pub(super) aliases: Aliases,
+    #[serde(default)]
+    pub(super) tracking_sheet_spec: Option<TrackingSheetSpecification>,
+}
+
+#[derive(Deserialize)]
+pub(super) struct TrackingSheetSpecification {
+    pub(super) id: String,
+    pub(super) google_sheets_api_key: String,
+    /// Override the Google Sheets API endpoint, which is useful for pointing at a local stand-in
+    #[serde(default)]
+    pub(super) google_sheets_api_url: Option<String>,
 }
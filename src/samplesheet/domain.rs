@@ -0,0 +1,84 @@
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+
+// Each of these enums is spelled exactly as it is in the Chromium tracking sheet. Other spellings can be mapped onto
+// these with the `samplesheet.aliases` table in the configuration file.
+macro_rules! tracking_sheet_enum {
+    ($(#[$meta:meta])* $name:ident { $($variant:ident => $value:literal),+ $(,)? }) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+        pub enum $name {
+            $(
+                #[serde(rename = $value)]
+                $variant
+            ),+
+        }
+
+        impl $name {
+            pub fn as_str(&self) -> &'static str {
+                match self {
+                    $(Self::$variant => $value),+
+                }
+            }
+        }
+
+        impl Display for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                self.as_str().fmt(f)
+            }
+        }
+    };
+}
+
+tracking_sheet_enum!(Species {
+    HomoSapiens => "Homo sapiens",
+    MusMusculus => "Mus musculus",
+});
+
+tracking_sheet_enum!(CellularMaterial {
+    Cells => "Cells",
+    Nuclei => "Nuclei",
+});
+
+tracking_sheet_enum!(Chemistry {
+    MultiplexFlexNextGem => "Multiplex Flex Gene Expression (Next GEM)",
+    MultiplexFlexGemX => "Multiplex Flex Gene Expression (GEM-X)",
+    MultiplexFlexGemXV2 => "Multiplex Flex Gene Expression v2 (GEM-X)",
+    SingleCell3PrimeV4 => "Single Cell 3' v4 (polyA)",
+    SingleCell3PrimeV4Ocm => "Single Cell 3' v4 (polyA) OCM",
+    SingleCell5PrimeV2 => "Single Cell 5' v2",
+    SingleCell5PrimeV3 => "Single Cell 5' v3",
+    SingleCellMultiomeArc => "Single Cell Multiome ATAC + Gene Expression",
+});
+
+tracking_sheet_enum!(LibraryType {
+    GeneExpression => "Gene Expression",
+    GeneExpressionFlex => "Gene Expression Flex",
+    AntibodyCapture => "Antibody Capture",
+    CrisprGuideCapture => "CRISPR Guide Capture",
+    MultiplexingCapture => "Multiplexing Capture",
+    VdjT => "VDJ-T",
+    VdjB => "VDJ-B",
+    VdjTGd => "VDJ-T-GD",
+    ChromatinAccessibility => "Chromatin Accessibility",
+});
+
+impl LibraryType {
+    /// The library type as cellranger (and therefore nf-tenx) understands it
+    pub fn cellranger_name(&self) -> &'static str {
+        match self {
+            Self::GeneExpressionFlex => Self::GeneExpression.as_str(),
+            _ => self.as_str(),
+        }
+    }
+
+    pub fn is_vdj(&self) -> bool {
+        matches!(self, Self::VdjT | Self::VdjB | Self::VdjTGd)
+    }
+
+    /// Whether the library's features have to be described by a feature reference
+    pub fn is_feature_barcoding(&self) -> bool {
+        matches!(self, Self::AntibodyCapture | Self::CrisprGuideCapture)
+    }
+}
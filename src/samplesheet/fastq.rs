@@ -0,0 +1,210 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::Display,
+    fs,
+    str::FromStr,
+    sync::LazyLock,
+};
+
+use anyhow::anyhow;
+use camino::{Utf8Path, Utf8PathBuf};
+use itertools::Itertools;
+use regex::Regex;
+
+use crate::problems::bail_on_problems;
+
+// Illumina's bcl2fastq and BCL Convert name FASTQ files as:
+// <LIBRARY ID>_S<SAMPLE NUMBER>_L<LANE>_<READ TYPE>_001.fastq.gz
+//
+// though some deliveries shorten the extension to `.fq.gz`.
+//
+// A Chromatin Accessibility (ATAC) library has an extra read, R3, as its R2 holds the 10x barcode
+static ILLUMINA_FASTQ_NAME: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^(?<library_id>.+)_S(?<sample_number>\d+)_L(?<lane>\d{3})_(?<read_type>[RI]\d)_001\.(?:fastq|fq)\.gz$")
+        .unwrap()
+});
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(super) enum ReadType {
+    R1,
+    R2,
+    R3,
+    I1,
+    I2,
+}
+
+impl FromStr for ReadType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "R1" => Ok(Self::R1),
+            "R2" => Ok(Self::R2),
+            "R3" => Ok(Self::R3),
+            "I1" => Ok(Self::I1),
+            "I2" => Ok(Self::I2),
+            _ => Err(anyhow!("unrecognized read type {s}")),
+        }
+    }
+}
+
+impl Display for ReadType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::R1 => "R1",
+            Self::R2 => "R2",
+            Self::R3 => "R3",
+            Self::I1 => "I1",
+            Self::I2 => "I2",
+        };
+
+        s.fmt(f)
+    }
+}
+
+#[derive(Debug)]
+pub(super) struct FastqFile<'a> {
+    pub(super) library_id: &'a str,
+    pub(super) lane: u16,
+    pub(super) read_type: ReadType,
+}
+
+impl<'a> FastqFile<'a> {
+    pub(super) fn from_path(path: &'a Utf8Path) -> anyhow::Result<Self> {
+        let filename = path.file_name().ok_or(anyhow!("malformed FASTQ path: {path}"))?;
+
+        let captures = ILLUMINA_FASTQ_NAME.captures(filename).ok_or(anyhow!(
+            "{path} does not follow the Illumina naming convention \
+             <LIBRARY ID>_S<SAMPLE NUMBER>_L<LANE>_<READ TYPE>_001.fastq.gz"
+        ))?;
+
+        // The regex guarantees that these groups exist
+        let library_id = captures.name("library_id").unwrap().as_str();
+        let lane = captures["lane"].parse()?;
+        let read_type = captures["read_type"].parse()?;
+
+        Ok(Self {
+            library_id,
+            lane,
+            read_type,
+        })
+    }
+}
+
+/// The read types present for each library among `fastq_paths`, which must already have passed [`validate`]. Paths
+/// that aren't FASTQ files are left out, as they are by [`validate`]
+pub(super) fn read_types_by_library(fastq_paths: &[Utf8PathBuf]) -> HashMap<&str, BTreeSet<ReadType>> {
+    let mut read_types: HashMap<_, BTreeSet<_>> = HashMap::new();
+
+    for file in fastq_paths
+        .iter()
+        .filter(|p| is_fastq(p))
+        .filter_map(|p| FastqFile::from_path(p).ok())
+    {
+        read_types.entry(file.library_id).or_default().insert(file.read_type);
+    }
+
+    read_types
+}
+
+pub(super) fn is_fastq(path: &Utf8Path) -> bool {
+    path.as_str().ends_with(".fastq.gz") || path.as_str().ends_with(".fq.gz")
+}
+
+/// Check that every path is a file, that every library has the same set of lanes for each of its reads, that no FASTQ
+/// file is empty, and that every FASTQ file can be attributed to a library, a lane, and a read type. Files that aren't
+/// FASTQ files (like checksums or reports that come with a delivery) are listed and otherwise ignored
+pub(super) fn validate(fastq_paths: &[Utf8PathBuf]) -> anyhow::Result<()> {
+    let mut problems = Vec::new();
+    let mut ignored_paths = Vec::new();
+    // A library sequenced more than once has a complete set of FASTQs in each delivery directory, so each directory is
+    // validated separately
+    let mut files_grouped_by_library_and_dir: BTreeMap<(&str, &Utf8Path), Vec<FastqFile>> = BTreeMap::new();
+
+    for path in fastq_paths {
+        if path.is_dir() {
+            problems.push(format!("FASTQ paths must point to files, but {path} is a directory"));
+            continue;
+        }
+        if !path.is_file() {
+            problems.push(format!("FASTQ paths must point to files, but {path} does not"));
+            continue;
+        }
+        if !is_fastq(path) {
+            ignored_paths.push(path);
+            continue;
+        }
+
+        let file = match FastqFile::from_path(path) {
+            Ok(f) => f,
+            Err(e) => {
+                problems.push(e.to_string());
+                continue;
+            }
+        };
+
+        match fs::metadata(path) {
+            Ok(metadata) if metadata.len() == 0 => problems.push(format!("{path} is empty")),
+            Ok(_) => {}
+            Err(e) => problems.push(format!("failed to read metadata for {path}: {e}")),
+        }
+
+        let dir = path.parent().unwrap_or(Utf8Path::new(""));
+
+        files_grouped_by_library_and_dir
+            .entry((file.library_id, dir))
+            .or_default()
+            .push(file);
+    }
+
+    for ((library_id, dir), files) in &files_grouped_by_library_and_dir {
+        problems.extend(validate_library(library_id, dir, files));
+    }
+
+    if !ignored_paths.is_empty() {
+        eprintln!(
+            "ignoring these paths, which aren't FASTQ files:\n{}",
+            ignored_paths.iter().map(|p| format!("  - {p}")).join("\n")
+        );
+    }
+
+    bail_on_problems("the FASTQ files", &problems)
+}
+
+fn validate_library(library_id: &str, dir: &Utf8Path, files: &[FastqFile]) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    let all_lanes: BTreeSet<_> = files.iter().map(|f| f.lane).collect();
+
+    let mut lanes_by_read_type: BTreeMap<ReadType, Vec<u16>> = BTreeMap::new();
+    for file in files {
+        lanes_by_read_type.entry(file.read_type).or_default().push(file.lane);
+    }
+
+    // Index reads are only required if the library was sequenced with them, in which case they must be present for
+    // every lane
+    for required in [ReadType::R1, ReadType::R2] {
+        lanes_by_read_type.entry(required).or_default();
+    }
+
+    for (read_type, lanes) in &lanes_by_read_type {
+        let present: BTreeSet<_> = lanes.iter().copied().collect();
+
+        let missing = all_lanes.difference(&present).map(|l| format!("L{l:03}")).join(", ");
+        if !missing.is_empty() {
+            problems.push(format!(
+                "library {library_id} in {dir} is missing {read_type} for lanes {missing}"
+            ));
+        }
+
+        for (lane, n_files) in lanes.iter().counts() {
+            if n_files > 1 {
+                problems.push(format!(
+                    "library {library_id} in {dir} has {n_files} {read_type} files for lane L{lane:03}"
+                ));
+            }
+        }
+    }
+
+    problems
+}
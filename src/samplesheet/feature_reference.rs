@@ -0,0 +1,155 @@
+use std::{collections::HashMap, fs};
+
+use anyhow::{Context, bail};
+use camino::{Utf8Path, Utf8PathBuf};
+
+use super::{domain::LibraryType, tracking_sheet::Feature};
+use crate::problems::bail_on_problems;
+
+// cellranger needs a feature reference for Antibody Capture and CRISPR Guide Capture libraries, which lists the
+// barcode of every feature in the panel. Panels are recorded in the tracking sheet's Feature Panels sheet against
+// either a GEMs or a suspension, so a GEMs' feature reference is made up of its own rows along with those of each of
+// its suspensions.
+const FEATURE_REFERENCE_FILENAME: &str = "feature_ref.csv";
+const TARGET_GENE_COLUMNS: [&str; 2] = ["target_gene_id", "target_gene_name"];
+
+pub(super) struct FeaturePanels<'a> {
+    by_gems_id: HashMap<&'a str, Vec<&'a Feature>>,
+    by_suspension_id: HashMap<&'a str, Vec<&'a Feature>>,
+}
+
+impl<'a> FeaturePanels<'a> {
+    /// Group the features by what they apply to, failing on any row that applies to nothing or isn't a feature
+    /// barcoding type
+    pub(super) fn new(features: &'a [Feature]) -> anyhow::Result<Self> {
+        let mut problems = Vec::new();
+        let mut by_gems_id: HashMap<_, Vec<_>> = HashMap::new();
+        let mut by_suspension_id: HashMap<_, Vec<_>> = HashMap::new();
+
+        for feature in features {
+            let Feature {
+                gems_id,
+                suspension_id,
+                id,
+                feature_type,
+                ..
+            } = feature;
+
+            if !feature_type.is_feature_barcoding() {
+                problems.push(format!(
+                    "feature {id} has type {feature_type}, which isn't a feature barcoding library type"
+                ));
+            }
+
+            match (gems_id, suspension_id) {
+                (Some(gems_id), _) => by_gems_id.entry(gems_id.as_str()).or_default().push(feature),
+                (None, Some(suspension_id)) => by_suspension_id
+                    .entry(suspension_id.as_str())
+                    .or_default()
+                    .push(feature),
+                (None, None) => problems.push(format!("feature {id} has neither a GEMs ID nor a suspension ID")),
+            }
+        }
+
+        bail_on_problems("the feature panels", &problems)?;
+
+        Ok(Self {
+            by_gems_id,
+            by_suspension_id,
+        })
+    }
+
+    /// The features of `feature_types` that apply to the GEMs or any of its suspensions. A feature listed more than
+    /// once (for example, for each suspension in a pool) is only included once, as long as every listing agrees
+    pub(super) fn for_gems(
+        &self,
+        gems_id: &str,
+        suspension_ids: &[&str],
+        feature_types: &[LibraryType],
+    ) -> anyhow::Result<Vec<&'a Feature>> {
+        let candidates = self
+            .by_gems_id
+            .get(gems_id)
+            .into_iter()
+            .chain(suspension_ids.iter().filter_map(|id| self.by_suspension_id.get(id)))
+            .flatten()
+            .filter(|f| feature_types.contains(&f.feature_type));
+
+        let mut features: Vec<&Feature> = Vec::new();
+        for candidate in candidates {
+            match features.iter().find(|f| f.id == candidate.id) {
+                Some(existing) if to_record(existing) == to_record(candidate) => {}
+                Some(_) => bail!(
+                    "feature {} is listed more than once for GEMs {gems_id} and its suspensions, with different values",
+                    candidate.id
+                ),
+                None => features.push(candidate),
+            }
+        }
+
+        for feature_type in feature_types {
+            if !features.iter().any(|f| f.feature_type == *feature_type) {
+                bail!(
+                    "GEMs {gems_id} has {feature_type} libraries, but there are no {feature_type} features for it \
+                     or its suspensions in the feature panels"
+                );
+            }
+        }
+
+        Ok(features)
+    }
+}
+
+/// Write `features` to `<dir>/<GEMS ID>/feature_ref.csv`, returning its absolute path
+pub(super) fn write(dir: &Utf8Path, gems_id: &str, features: &[&Feature]) -> anyhow::Result<Utf8PathBuf> {
+    let gems_dir = dir.join(gems_id);
+    fs::create_dir_all(&gems_dir).context(format!("failed to create directory {gems_dir}"))?;
+
+    // The target gene columns are only for CRISPR guides, so they're left out unless something uses them
+    let has_target_genes = features
+        .iter()
+        .any(|f| f.target_gene_id.is_some() || f.target_gene_name.is_some());
+
+    let mut writer = csv::Writer::from_writer(Vec::new());
+
+    let mut header = vec!["id", "name", "read", "pattern", "sequence", "feature_type"];
+    if has_target_genes {
+        header.extend(TARGET_GENE_COLUMNS);
+    }
+    writer.write_record(&header)?;
+
+    for feature in features {
+        writer.write_record(&to_record(feature)[..header.len()])?;
+    }
+
+    let path = gems_dir.join(FEATURE_REFERENCE_FILENAME);
+    fs::write(&path, writer.into_inner()?).context(format!("failed to write feature reference to {path}"))?;
+
+    path.canonicalize_utf8()
+        .context(format!("failed to get absolute path for {path}"))
+}
+
+fn to_record(feature: &Feature) -> [&str; 8] {
+    let Feature {
+        id,
+        name,
+        read,
+        pattern,
+        sequence,
+        feature_type,
+        target_gene_id,
+        target_gene_name,
+        ..
+    } = feature;
+
+    [
+        id,
+        name,
+        read,
+        pattern,
+        sequence,
+        feature_type.as_str(),
+        target_gene_id.as_deref().unwrap_or_default(),
+        target_gene_name.as_deref().unwrap_or_default(),
+    ]
+}
@@ -0,0 +1,354 @@
+use anyhow::anyhow;
+use camino::Utf8Path;
+use itertools::Itertools;
+use serde::Serialize;
+
+use super::Samplesheet;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum SamplesheetFormat {
+    Yaml,
+    Json,
+    Csv,
+}
+
+impl SamplesheetFormat {
+    pub(super) fn from_path(path: &Utf8Path) -> anyhow::Result<Self> {
+        match path.extension() {
+            Some("yaml" | "yml") => Ok(Self::Yaml),
+            Some("json") => Ok(Self::Json),
+            Some("csv") => Ok(Self::Csv),
+            _ => Err(anyhow!(
+                "failed to infer samplesheet format from {path}. Use a '.yaml', '.yml', '.json', or '.csv' extension, or \
+                 specify the format explicitly"
+            )),
+        }
+    }
+
+    pub(super) fn serialize(&self, samplesheets: &[Samplesheet]) -> anyhow::Result<String> {
+        let serialized = match self {
+            Self::Yaml => serde_yaml::to_string(samplesheets)?,
+            Self::Json => serde_json::to_string_pretty(samplesheets)?,
+            Self::Csv => {
+                let mut writer = csv::Writer::from_writer(Vec::new());
+                for samplesheet in samplesheets {
+                    writer.serialize(CsvRow::try_from(samplesheet)?)?;
+                }
+
+                String::from_utf8(writer.into_inner()?)?
+            }
+        };
+
+        Ok(serialized)
+    }
+}
+
+const LIST_SEPARATOR: &str = ";";
+const NESTED_LIST_SEPARATOR: &str = ",";
+
+// CSV can't represent nested data, so list fields are joined with a separator (the FASTQ directories of a single library
+// with a different one) and the design is embedded as JSON
+#[derive(Serialize)]
+struct CsvRow<'a> {
+    libraries: String,
+    sample_name: &'a str,
+    library_types: String,
+    is_nuclei: bool,
+    tool: &'a str,
+    tool_version: &'a str,
+    command: &'a str,
+    reference_path: &'a Utf8Path,
+    probe_set: Option<&'a Utf8Path>,
+    feature_reference: Option<&'a Utf8Path>,
+    vdj_reference: Option<&'a Utf8Path>,
+    inner_enrichment_primers: Option<&'a Utf8Path>,
+    design: Option<String>,
+    fastq_paths: String,
+}
+
+impl<'a> TryFrom<&'a Samplesheet<'a>> for CsvRow<'a> {
+    type Error = serde_json::Error;
+
+    fn try_from(samplesheet: &'a Samplesheet<'a>) -> Result<Self, Self::Error> {
+        let Samplesheet {
+            libraries,
+            sample_name,
+            library_types,
+            is_nuclei,
+            tool,
+            tool_version,
+            command,
+            reference_path,
+            probe_set,
+            feature_reference,
+            vdj_reference,
+            inner_enrichment_primers,
+            design,
+            fastq_paths,
+        } = samplesheet;
+
+        Ok(Self {
+            libraries: libraries.join(LIST_SEPARATOR),
+            sample_name,
+            library_types: library_types.join(LIST_SEPARATOR),
+            is_nuclei: *is_nuclei,
+            tool,
+            tool_version,
+            command,
+            reference_path,
+            probe_set: probe_set.map(|p| p.as_path()),
+            feature_reference: feature_reference.as_deref(),
+            vdj_reference: vdj_reference.map(|p| p.as_path()),
+            inner_enrichment_primers: inner_enrichment_primers.map(|p| p.as_path()),
+            design: design.as_ref().map(serde_json::to_string).transpose()?,
+            fastq_paths: fastq_paths
+                .iter()
+                .map(|dirs| dirs.iter().join(NESTED_LIST_SEPARATOR))
+                .join(LIST_SEPARATOR),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use camino::Utf8PathBuf;
+    use pretty_assertions::assert_eq;
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::samplesheet::SampleDesign;
+
+    /// An owned copy of a `Samplesheet`, which is what a reader of the samplesheet gets back
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct ReadSamplesheet {
+        libraries: Vec<String>,
+        sample_name: String,
+        library_types: Vec<String>,
+        is_nuclei: bool,
+        tool: String,
+        tool_version: String,
+        command: String,
+        reference_path: Utf8PathBuf,
+        probe_set: Option<Utf8PathBuf>,
+        feature_reference: Option<Utf8PathBuf>,
+        vdj_reference: Option<Utf8PathBuf>,
+        inner_enrichment_primers: Option<Utf8PathBuf>,
+        design: Option<HashMap<String, ReadSampleDesign>>,
+        fastq_paths: Vec<Vec<Utf8PathBuf>>,
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct ReadSampleDesign {
+        name: String,
+        description: String,
+    }
+
+    #[derive(Deserialize)]
+    struct ReadCsvRow {
+        libraries: String,
+        sample_name: String,
+        library_types: String,
+        is_nuclei: bool,
+        tool: String,
+        tool_version: String,
+        command: String,
+        reference_path: Utf8PathBuf,
+        probe_set: Option<Utf8PathBuf>,
+        feature_reference: Option<Utf8PathBuf>,
+        vdj_reference: Option<Utf8PathBuf>,
+        inner_enrichment_primers: Option<Utf8PathBuf>,
+        design: Option<String>,
+        fastq_paths: String,
+    }
+
+    impl From<&Samplesheet<'_>> for ReadSamplesheet {
+        fn from(samplesheet: &Samplesheet) -> Self {
+            let Samplesheet {
+                libraries,
+                sample_name,
+                library_types,
+                is_nuclei,
+                tool,
+                tool_version,
+                command,
+                reference_path,
+                probe_set,
+                feature_reference,
+                vdj_reference,
+                inner_enrichment_primers,
+                design,
+                fastq_paths,
+            } = samplesheet;
+
+            Self {
+                libraries: libraries.iter().map(|l| l.to_string()).collect(),
+                sample_name: sample_name.to_string(),
+                library_types: library_types.iter().map(|t| t.to_string()).collect(),
+                is_nuclei: *is_nuclei,
+                tool: tool.to_string(),
+                tool_version: tool_version.to_string(),
+                command: command.to_string(),
+                reference_path: reference_path.to_path_buf(),
+                probe_set: probe_set.cloned(),
+                feature_reference: feature_reference.clone(),
+                vdj_reference: vdj_reference.cloned(),
+                inner_enrichment_primers: inner_enrichment_primers.cloned(),
+                design: design.as_ref().map(|design| {
+                    design
+                        .iter()
+                        .map(|(tag_id, SampleDesign { name, description })| {
+                            (
+                                tag_id.clone(),
+                                ReadSampleDesign {
+                                    name: name.to_string(),
+                                    description: description.to_string(),
+                                },
+                            )
+                        })
+                        .collect()
+                }),
+                fastq_paths: fastq_paths
+                    .iter()
+                    .map(|dirs| dirs.iter().map(|d| d.to_path_buf()).collect())
+                    .collect(),
+            }
+        }
+    }
+
+    impl From<ReadCsvRow> for ReadSamplesheet {
+        fn from(row: ReadCsvRow) -> Self {
+            let split = |list: &str| list.split(LIST_SEPARATOR).map(str::to_string).collect();
+
+            Self {
+                libraries: split(&row.libraries),
+                sample_name: row.sample_name,
+                library_types: split(&row.library_types),
+                is_nuclei: row.is_nuclei,
+                tool: row.tool,
+                tool_version: row.tool_version,
+                command: row.command,
+                reference_path: row.reference_path,
+                probe_set: row.probe_set,
+                feature_reference: row.feature_reference,
+                vdj_reference: row.vdj_reference,
+                inner_enrichment_primers: row.inner_enrichment_primers,
+                design: row.design.map(|d| serde_json::from_str(&d).unwrap()),
+                fastq_paths: row
+                    .fastq_paths
+                    .split(LIST_SEPARATOR)
+                    .map(|dirs| dirs.split(NESTED_LIST_SEPARATOR).map(Utf8PathBuf::from).collect())
+                    .collect(),
+            }
+        }
+    }
+
+    fn read(format: SamplesheetFormat, serialized: &str) -> Vec<ReadSamplesheet> {
+        match format {
+            SamplesheetFormat::Yaml => serde_yaml::from_str(serialized).unwrap(),
+            SamplesheetFormat::Json => serde_json::from_str(serialized).unwrap(),
+            SamplesheetFormat::Csv => csv::Reader::from_reader(serialized.as_bytes())
+                .deserialize::<ReadCsvRow>()
+                .map(|row| row.unwrap().into())
+                .collect(),
+        }
+    }
+
+    fn round_trip(format: SamplesheetFormat) {
+        let reference_path = Utf8Path::new("/references/refdata-gex-GRCh38-2020-A");
+        let probe_set = Utf8PathBuf::from("/references/probe-set.csv");
+        let vdj_reference = Utf8PathBuf::from("/references/refdata-cellranger-vdj-GRCh38");
+
+        // A library sequenced more than once has a FASTQ directory for each run
+        let gex_dirs = [Utf8Path::new("/fastqs/run1"), Utf8Path::new("/fastqs/run2")];
+        let antibody_dirs = [Utf8Path::new("/fastqs/run1")];
+        let vdj_dirs = [Utf8Path::new("/fastqs/run3")];
+
+        let samplesheets = [
+            Samplesheet {
+                libraries: vec!["L1", "L2"],
+                sample_name: "pool",
+                library_types: vec!["Gene Expression", "Antibody Capture"],
+                is_nuclei: false,
+                tool: "cellranger",
+                tool_version: "9.0.1",
+                command: "multi",
+                reference_path,
+                probe_set: Some(&probe_set),
+                feature_reference: Some(Utf8PathBuf::from("/feature-references/G1/feature_ref.csv")),
+                vdj_reference: None,
+                inner_enrichment_primers: None,
+                design: Some(HashMap::from([
+                    (
+                        "BC001".to_string(),
+                        SampleDesign {
+                            name: "sample1",
+                            description: "Lung",
+                        },
+                    ),
+                    (
+                        "BC002".to_string(),
+                        SampleDesign {
+                            name: "sample2",
+                            description: "Liver",
+                        },
+                    ),
+                ])),
+                fastq_paths: vec![&gex_dirs, &antibody_dirs],
+            },
+            Samplesheet {
+                libraries: vec!["L3"],
+                sample_name: "tcells",
+                library_types: vec!["VDJ-T"],
+                is_nuclei: true,
+                tool: "cellranger",
+                tool_version: "9.0.1",
+                command: "multi",
+                reference_path,
+                probe_set: None,
+                feature_reference: None,
+                vdj_reference: Some(&vdj_reference),
+                inner_enrichment_primers: None,
+                design: None,
+                fastq_paths: vec![&vdj_dirs],
+            },
+        ];
+
+        let serialized = format.serialize(&samplesheets).unwrap();
+
+        assert_eq!(
+            read(format, &serialized),
+            samplesheets.iter().map(ReadSamplesheet::from).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn yaml_round_trip() {
+        round_trip(SamplesheetFormat::Yaml);
+    }
+
+    #[test]
+    fn json_round_trip() {
+        round_trip(SamplesheetFormat::Json);
+    }
+
+    #[test]
+    fn csv_round_trip() {
+        round_trip(SamplesheetFormat::Csv);
+    }
+
+    #[test]
+    fn infers_format_from_extension() {
+        for (path, format) in [
+            ("samplesheet.yaml", SamplesheetFormat::Yaml),
+            ("samplesheet.yml", SamplesheetFormat::Yaml),
+            ("samplesheet.json", SamplesheetFormat::Json),
+            ("samplesheet.csv", SamplesheetFormat::Csv),
+        ] {
+            assert_eq!(SamplesheetFormat::from_path(Utf8Path::new(path)).unwrap(), format);
+        }
+
+        assert!(SamplesheetFormat::from_path(Utf8Path::new("samplesheet.txt")).is_err());
+    }
+}
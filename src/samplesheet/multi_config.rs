@@ -0,0 +1,285 @@
+use std::fs;
+
+use anyhow::Context;
+use camino::Utf8Path;
+use itertools::Itertools;
+
+use super::{Samplesheet, domain::LibraryType, tag::SampleBarcode};
+
+// A cellranger multi config is a CSV with a section for each kind of setting:
+// [gene-expression]
+// reference,<REFERENCE PATH>
+// probe-set,<PROBE SET PATH>
+// [feature]
+// reference,<FEATURE REFERENCE PATH>
+// [vdj]
+// reference,<VDJ REFERENCE PATH>
+// inner-enrichment-primers,<PRIMERS PATH>
+// [libraries]
+// fastq_id,fastqs,feature_types
+// <LIBRARY ID>,<FASTQ DIR>,<LIBRARY TYPE>
+// [samples]
+// sample_id,<BARCODE COLUMN>,description
+// <SAMPLE NAME>,<TAG ID>,<TISSUE>
+//
+// nf-tenx builds the same file from a samplesheet entry, so writing it here makes it possible to check what the
+// pipeline will do, and to run cellranger by hand when debugging.
+
+/// Write the multi config for `samplesheet` to `<dir>/<GEMS ID>.csv`
+pub(super) fn write(
+    dir: &Utf8Path,
+    gems_id: &str,
+    samplesheet: &Samplesheet,
+    sample_barcode: Option<SampleBarcode>,
+) -> anyhow::Result<()> {
+    let path = dir.join(format!("{gems_id}.csv"));
+    let contents =
+        to_csv(samplesheet, sample_barcode).context(format!("failed to build multi config for {gems_id}"))?;
+
+    fs::write(&path, contents).context(format!("failed to write multi config to {path}"))
+}
+
+fn to_csv(samplesheet: &Samplesheet, sample_barcode: Option<SampleBarcode>) -> anyhow::Result<String> {
+    let Samplesheet {
+        libraries,
+        library_types,
+        tool_version,
+        reference_path,
+        probe_set,
+        feature_reference,
+        vdj_reference,
+        inner_enrichment_primers,
+        design,
+        fastq_paths,
+        ..
+    } = samplesheet;
+
+    // The sections have different numbers of columns
+    let mut writer = csv::WriterBuilder::new().flexible(true).from_writer(Vec::new());
+
+    // A GEMs with only VDJ libraries has no use for a gene expression reference, and cellranger rejects one
+    let vdj_types = [LibraryType::VdjT, LibraryType::VdjB, LibraryType::VdjTGd].map(|t| t.cellranger_name());
+    if library_types.iter().any(|t| !vdj_types.contains(t)) {
+        writer.write_record(["[gene-expression]"])?;
+        writer.write_record(["reference", reference_path.as_str()])?;
+        if let Some(probe_set) = probe_set {
+            writer.write_record(["probe-set", probe_set.as_str()])?;
+        }
+        // Starting with cellranger 8, whether to create a BAM file must be stated explicitly
+        if major_version(tool_version).is_some_and(|v| v >= 8) {
+            writer.write_record(["create-bam", "true"])?;
+        }
+    }
+
+    if let Some(feature_reference) = feature_reference {
+        writer.write_record(["[feature]"])?;
+        writer.write_record(["reference", feature_reference.as_str()])?;
+    }
+
+    if let Some(vdj_reference) = vdj_reference {
+        writer.write_record(["[vdj]"])?;
+        writer.write_record(["reference", vdj_reference.as_str()])?;
+        if let Some(inner_enrichment_primers) = inner_enrichment_primers {
+            writer.write_record(["inner-enrichment-primers", inner_enrichment_primers.as_str()])?;
+        }
+    }
+
+    writer.write_record(["[libraries]"])?;
+    writer.write_record(["fastq_id", "fastqs", "feature_types"])?;
+    for ((library_id, library_type), dirs) in libraries.iter().zip_eq(library_types).zip_eq(fastq_paths) {
+        // A library sequenced more than once is listed once for each of its FASTQ directories, which are made
+        // absolute so that the config can be run from anywhere
+        for dir in dirs.iter() {
+            let dir = dir
+                .canonicalize_utf8()
+                .context(format!("failed to get absolute path for {dir}"))?;
+            writer.write_record([library_id, dir.as_str(), library_type])?;
+        }
+    }
+
+    if let (Some(design), Some(sample_barcode)) = (design, sample_barcode) {
+        writer.write_record(["[samples]"])?;
+        writer.write_record(["sample_id", sample_barcode.column(), "description"])?;
+        for (tag_id, sample) in design.iter().sorted_by_key(|(t, _)| *t) {
+            writer.write_record([sample.name, tag_id, sample.description])?;
+        }
+    }
+
+    Ok(String::from_utf8(writer.into_inner()?)?)
+}
+
+fn major_version(version: &str) -> Option<u32> {
+    version.split('.').next()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use camino::Utf8PathBuf;
+    use pretty_assertions::assert_eq;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::samplesheet::SampleDesign;
+
+    /// Render the multi config of a GEMs with one library of each of `library_types`, all in one FASTQ directory, and
+    /// two samples told apart by `tag_ids` if it has a `sample_barcode`. The FASTQ directory is shown as `<FASTQS>`
+    fn render(
+        library_types: &[&str],
+        tool_version: &str,
+        sample_barcode: Option<SampleBarcode>,
+        tag_ids: [&str; 2],
+    ) -> String {
+        let dir = TempDir::new().unwrap();
+        let fastq_dir = Utf8PathBuf::from_path_buf(dir.path().canonicalize().unwrap()).unwrap();
+        let fastq_dirs = [fastq_dir.as_path()];
+
+        let library_ids = ["L1", "L2", "L3"];
+        let probe_set = Utf8PathBuf::from("/references/probe-set.csv");
+        let vdj_reference = Utf8PathBuf::from("/references/refdata-cellranger-vdj");
+        let primers = Utf8PathBuf::from("/references/primers.txt");
+        let has = |library_type: LibraryType| library_types.contains(&library_type.cellranger_name());
+
+        let samplesheet = Samplesheet {
+            libraries: library_ids[..library_types.len()].to_vec(),
+            sample_name: "pool",
+            library_types: library_types.to_vec(),
+            is_nuclei: false,
+            tool: "cellranger",
+            tool_version,
+            command: "multi",
+            reference_path: Utf8Path::new("/references/refdata-gex"),
+            probe_set: matches!(sample_barcode, Some(SampleBarcode::Probe { .. })).then_some(&probe_set),
+            feature_reference: (has(LibraryType::AntibodyCapture) || has(LibraryType::MultiplexingCapture))
+                .then(|| Utf8PathBuf::from("/feature-references/feature_ref.csv")),
+            vdj_reference: has(LibraryType::VdjT).then_some(&vdj_reference),
+            inner_enrichment_primers: has(LibraryType::VdjT).then_some(&primers),
+            design: sample_barcode.map(|_| {
+                HashMap::from([
+                    (
+                        tag_ids[0].to_string(),
+                        SampleDesign {
+                            name: "sample1",
+                            description: "Lung",
+                        },
+                    ),
+                    (
+                        tag_ids[1].to_string(),
+                        SampleDesign {
+                            name: "sample2",
+                            description: "Liver",
+                        },
+                    ),
+                ])
+            }),
+            fastq_paths: vec![&fastq_dirs; library_types.len()],
+        };
+
+        to_csv(&samplesheet, sample_barcode)
+            .unwrap()
+            .replace(fastq_dir.as_str(), "<FASTQS>")
+    }
+
+    #[test]
+    fn flex() {
+        assert_eq!(
+            render(
+                &["Gene Expression"],
+                "9.0.1",
+                Some(SampleBarcode::Probe { plate_based: false }),
+                ["BC001", "BC002"]
+            ),
+            "[gene-expression]\n\
+             reference,/references/refdata-gex\n\
+             probe-set,/references/probe-set.csv\n\
+             create-bam,true\n\
+             [libraries]\n\
+             fastq_id,fastqs,feature_types\n\
+             L1,<FASTQS>,Gene Expression\n\
+             [samples]\n\
+             sample_id,probe_barcode_ids,description\n\
+             sample1,BC001,Lung\n\
+             sample2,BC002,Liver\n"
+        );
+    }
+
+    #[test]
+    fn cellplex_before_cellranger_8() {
+        assert_eq!(
+            render(
+                &["Gene Expression", "Multiplexing Capture"],
+                "7.2.0",
+                Some(SampleBarcode::Cmo),
+                ["CMO301", "CMO302"]
+            ),
+            "[gene-expression]\n\
+             reference,/references/refdata-gex\n\
+             [feature]\n\
+             reference,/feature-references/feature_ref.csv\n\
+             [libraries]\n\
+             fastq_id,fastqs,feature_types\n\
+             L1,<FASTQS>,Gene Expression\n\
+             L2,<FASTQS>,Multiplexing Capture\n\
+             [samples]\n\
+             sample_id,cmo_ids,description\n\
+             sample1,CMO301,Lung\n\
+             sample2,CMO302,Liver\n"
+        );
+    }
+
+    #[test]
+    fn ocm() {
+        assert_eq!(
+            render(&["Gene Expression"], "9.0.1", Some(SampleBarcode::Ocm), ["OB1", "OB2"]),
+            "[gene-expression]\n\
+             reference,/references/refdata-gex\n\
+             create-bam,true\n\
+             [libraries]\n\
+             fastq_id,fastqs,feature_types\n\
+             L1,<FASTQS>,Gene Expression\n\
+             [samples]\n\
+             sample_id,ocm_barcode_ids,description\n\
+             sample1,OB1,Lung\n\
+             sample2,OB2,Liver\n"
+        );
+    }
+
+    #[test]
+    fn antibody_hashing() {
+        assert_eq!(
+            render(
+                &["Gene Expression", "Antibody Capture"],
+                "10.0.0",
+                Some(SampleBarcode::Hashtag),
+                ["Hashtag1", "Hashtag2"]
+            ),
+            "[gene-expression]\n\
+             reference,/references/refdata-gex\n\
+             create-bam,true\n\
+             [feature]\n\
+             reference,/feature-references/feature_ref.csv\n\
+             [libraries]\n\
+             fastq_id,fastqs,feature_types\n\
+             L1,<FASTQS>,Gene Expression\n\
+             L2,<FASTQS>,Antibody Capture\n\
+             [samples]\n\
+             sample_id,hashtag_ids,description\n\
+             sample1,Hashtag1,Lung\n\
+             sample2,Hashtag2,Liver\n"
+        );
+    }
+
+    #[test]
+    fn vdj_only() {
+        assert_eq!(
+            render(&["VDJ-T"], "9.0.1", None, ["", ""]),
+            "[vdj]\n\
+             reference,/references/refdata-cellranger-vdj\n\
+             inner-enrichment-primers,/references/primers.txt\n\
+             [libraries]\n\
+             fastq_id,fastqs,feature_types\n\
+             L1,<FASTQS>,VDJ-T\n"
+        );
+    }
+}
@@ -0,0 +1,112 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+};
+
+use anyhow::Context;
+use camino::Utf8Path;
+use itertools::Itertools;
+use serde::Serialize;
+
+use super::tracking_sheet::{Id, Library};
+
+/// The differences between the libraries in the tracking sheet and the libraries found among the FASTQ files
+#[derive(Serialize)]
+pub(super) struct ReconciliationReport<'a> {
+    /// Library IDs parsed from FASTQ files that are not in the tracking sheet
+    unmatched_fastq_library_ids: Vec<&'a str>,
+    /// GEMs for which some, but not all, libraries have FASTQ files
+    incomplete_gems: Vec<IncompleteGems<'a>>,
+}
+
+#[derive(Serialize)]
+struct IncompleteGems<'a> {
+    gems_id: &'a str,
+    present_libraries: Vec<LibrarySummary<'a>>,
+    missing_libraries: Vec<LibrarySummary<'a>>,
+}
+
+#[derive(Serialize)]
+struct LibrarySummary<'a> {
+    id: &'a str,
+    #[serde(rename = "type")]
+    type_: &'a str,
+}
+
+impl<'a> From<&'a Library> for LibrarySummary<'a> {
+    fn from(library: &'a Library) -> Self {
+        Self {
+            id: library.id(),
+            type_: library.type_.as_str(),
+        }
+    }
+}
+
+impl<'a> ReconciliationReport<'a> {
+    pub(super) fn new<T>(libraries: &'a [Library], fastq_library_ids: &HashMap<&'a str, T>) -> Self {
+        let tracking_sheet_library_ids: HashSet<_> = libraries.iter().map(|l| l.id()).collect();
+
+        let unmatched_fastq_library_ids = fastq_library_ids
+            .keys()
+            .copied()
+            .filter(|id| !tracking_sheet_library_ids.contains(id))
+            .sorted()
+            .collect();
+
+        let incomplete_gems = libraries
+            .iter()
+            .into_group_map_by(|l| l.gems_id.as_str())
+            .into_iter()
+            .filter_map(|(gems_id, libs)| {
+                let (present, missing): (Vec<_>, Vec<_>) =
+                    libs.into_iter().partition(|l| fastq_library_ids.contains_key(l.id()));
+
+                (!present.is_empty() && !missing.is_empty()).then(|| IncompleteGems {
+                    gems_id,
+                    present_libraries: present.into_iter().map(LibrarySummary::from).collect(),
+                    missing_libraries: missing.into_iter().map(LibrarySummary::from).collect(),
+                })
+            })
+            .sorted_by_key(|g| g.gems_id)
+            .collect();
+
+        Self {
+            unmatched_fastq_library_ids,
+            incomplete_gems,
+        }
+    }
+
+    pub(super) fn print(&self) {
+        let Self {
+            unmatched_fastq_library_ids,
+            incomplete_gems,
+        } = self;
+
+        if !unmatched_fastq_library_ids.is_empty() {
+            eprintln!(
+                "the following library IDs were found among the FASTQ files but not in the tracking sheet, so they were \
+                 left out of the samplesheet: {}",
+                unmatched_fastq_library_ids.join(", ")
+            );
+        }
+
+        let format_libraries =
+            |libs: &[LibrarySummary]| libs.iter().map(|l| format!("{} ({})", l.id, l.type_)).join(", ");
+        for IncompleteGems {
+            gems_id,
+            present_libraries,
+            missing_libraries,
+        } in incomplete_gems
+        {
+            eprintln!(
+                "GEMs {gems_id} is only partly present: found FASTQ files for {}, but not for {}",
+                format_libraries(present_libraries),
+                format_libraries(missing_libraries)
+            );
+        }
+    }
+
+    pub(super) fn write_json(&self, path: &Utf8Path) -> anyhow::Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?).context(format!("failed to write report to {path}"))
+    }
+}
@@ -0,0 +1,217 @@
+use std::ops::RangeInclusive;
+
+use anyhow::{anyhow, ensure};
+
+use super::domain::{Chemistry, LibraryType};
+
+// The samples in a pool are told apart by a tag, whose kind depends on the chemistry and libraries of the GEMs:
+// - Flex pools use probe barcodes, BC001-BC016. GEM-X Flex v2 adds plate-based barcodes, so its range extends to
+//   BC384 (four 96-well plates)
+// - CellPlex pools use CMOs, CMO301-CMO312
+// - OCM pools use OCM barcodes, OB1-OB4
+// - pools hashed with antibodies use hashtags, which are the IDs of Antibody Capture features in the feature reference
+//
+// Tags are typed by hand into the tracking sheet, so spellings like `bc1` or `CMO 301` are normalized to the one
+// cellranger expects.
+const PROBE_BARCODES: RangeInclusive<u16> = 1..=16;
+const PLATE_PROBE_BARCODES: RangeInclusive<u16> = 1..=384;
+const CMOS: RangeInclusive<u16> = 301..=312;
+const OCM_BARCODES: RangeInclusive<u16> = 1..=4;
+
+/// The kind of tag that tells the samples in a GEMs apart, which determines the column of the multi config's
+/// `[samples]` section that holds it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum SampleBarcode {
+    Probe { plate_based: bool },
+    Cmo,
+    Ocm,
+    Hashtag,
+}
+
+impl SampleBarcode {
+    /// The kind of tag that tells apart the samples in a multiplexed (rather than OCM) pool. A Flex pool is told apart
+    /// by probe barcodes, and a pool with Antibody Capture libraries but no Multiplexing Capture libraries by hashtags
+    /// rather than CMOs
+    pub(super) fn for_pool(chemistry: Chemistry, library_types: &[LibraryType]) -> Self {
+        let has = |library_type| library_types.contains(&library_type);
+
+        if has(LibraryType::GeneExpressionFlex) {
+            Self::Probe {
+                plate_based: chemistry == Chemistry::MultiplexFlexGemXV2,
+            }
+        } else if has(LibraryType::AntibodyCapture) && !has(LibraryType::MultiplexingCapture) {
+            Self::Hashtag
+        } else {
+            Self::Cmo
+        }
+    }
+
+    pub(super) fn column(self) -> &'static str {
+        match self {
+            Self::Probe { .. } => "probe_barcode_ids",
+            Self::Cmo => "cmo_ids",
+            Self::Ocm => "ocm_barcode_ids",
+            Self::Hashtag => "hashtag_ids",
+        }
+    }
+
+    /// Normalize `tag_id` to the spelling cellranger expects, or explain why it isn't a tag of this kind
+    pub(super) fn normalize(self, tag_id: &str) -> anyhow::Result<String> {
+        let (prefix, range, description) = match self {
+            Self::Probe { plate_based: false } => ("BC", PROBE_BARCODES, "a Flex probe barcode (BC001-BC016)"),
+            Self::Probe { plate_based: true } => (
+                "BC",
+                PLATE_PROBE_BARCODES,
+                "a GEM-X Flex v2 probe barcode (BC001-BC384)",
+            ),
+            Self::Cmo => ("CMO", CMOS, "a CellPlex CMO (CMO301-CMO312)"),
+            Self::Ocm => ("OB", OCM_BARCODES, "an OCM barcode (OB1-OB4)"),
+            Self::Hashtag => return normalize_hashtag(tag_id),
+        };
+
+        let compact: String = tag_id
+            .chars()
+            .filter(|c| !(c.is_whitespace() || *c == '-' || *c == '_'))
+            .collect::<String>()
+            .to_ascii_uppercase();
+
+        let number = compact
+            .strip_prefix(prefix)
+            .filter(|digits| !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()))
+            .and_then(|digits| digits.parse::<u16>().ok())
+            .filter(|n| range.contains(n))
+            .ok_or(anyhow!("{tag_id} isn't {description}"))?;
+
+        Ok(match self {
+            Self::Probe { .. } => format!("{prefix}{number:03}"),
+            _ => format!("{prefix}{number}"),
+        })
+    }
+}
+
+/// A hashtag is a feature ID, so it's kept as is, apart from surrounding whitespace. It ends up in a CSV, so it can't
+/// contain whitespace or commas
+fn normalize_hashtag(tag_id: &str) -> anyhow::Result<String> {
+    let tag_id = tag_id.trim();
+    ensure!(
+        !tag_id.is_empty() && !tag_id.contains(|c: char| c.is_whitespace() || c == ','),
+        "{tag_id:?} isn't a valid hashtag, which must be a feature ID with no whitespace or commas"
+    );
+
+    Ok(tag_id.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    const FLEX: SampleBarcode = SampleBarcode::Probe { plate_based: false };
+    const PLATE_FLEX: SampleBarcode = SampleBarcode::Probe { plate_based: true };
+
+    #[test]
+    fn normalizes_valid_spellings() {
+        for (sample_barcode, tag_id, expected) in [
+            (FLEX, "BC001", "BC001"),
+            (FLEX, "bc1", "BC001"),
+            (FLEX, " BC-16 ", "BC016"),
+            (FLEX, "bc_010", "BC010"),
+            (PLATE_FLEX, "BC17", "BC017"),
+            (PLATE_FLEX, "bc384", "BC384"),
+            (SampleBarcode::Cmo, "CMO301", "CMO301"),
+            (SampleBarcode::Cmo, "CMO 301", "CMO301"),
+            (SampleBarcode::Cmo, "cmo-312", "CMO312"),
+            (SampleBarcode::Ocm, "OB1", "OB1"),
+            (SampleBarcode::Ocm, "ob 4", "OB4"),
+            (SampleBarcode::Ocm, "OB04", "OB4"),
+            (SampleBarcode::Hashtag, " Hashtag_1 ", "Hashtag_1"),
+            (SampleBarcode::Hashtag, "TotalSeq-C0251", "TotalSeq-C0251"),
+        ] {
+            assert_eq!(
+                sample_barcode.normalize(tag_id).unwrap(),
+                expected,
+                "{sample_barcode:?} {tag_id:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_out_of_range_tags() {
+        for (sample_barcode, tag_id) in [
+            (FLEX, "BC000"),
+            (FLEX, "BC017"),
+            (PLATE_FLEX, "BC385"),
+            (SampleBarcode::Cmo, "CMO300"),
+            (SampleBarcode::Cmo, "CMO313"),
+            (SampleBarcode::Ocm, "OB0"),
+            (SampleBarcode::Ocm, "OB5"),
+            (FLEX, "BC99999"),
+        ] {
+            assert!(
+                sample_barcode.normalize(tag_id).is_err(),
+                "{sample_barcode:?} {tag_id:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_tags_of_the_wrong_kind() {
+        for (sample_barcode, tag_id) in [
+            (FLEX, "CMO301"),
+            (FLEX, "OB1"),
+            (FLEX, "1"),
+            (FLEX, "BC1A"),
+            (SampleBarcode::Cmo, "BC001"),
+            (SampleBarcode::Cmo, "OB1"),
+            (SampleBarcode::Ocm, "BC001"),
+            (SampleBarcode::Ocm, "CMO301"),
+            (SampleBarcode::Hashtag, ""),
+            (SampleBarcode::Hashtag, "Hashtag 1"),
+            (SampleBarcode::Hashtag, "Hashtag1,Hashtag2"),
+        ] {
+            assert!(
+                sample_barcode.normalize(tag_id).is_err(),
+                "{sample_barcode:?} {tag_id:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn chooses_tag_kind_for_pool() {
+        use Chemistry::*;
+        use LibraryType::*;
+
+        for (chemistry, library_types, expected) in [
+            (MultiplexFlexGemX, vec![GeneExpressionFlex], FLEX),
+            (MultiplexFlexNextGem, vec![GeneExpressionFlex, AntibodyCapture], FLEX),
+            (MultiplexFlexGemXV2, vec![GeneExpressionFlex], PLATE_FLEX),
+            (
+                SingleCell3PrimeV4,
+                vec![GeneExpression, MultiplexingCapture],
+                SampleBarcode::Cmo,
+            ),
+            (
+                SingleCell3PrimeV4,
+                vec![GeneExpression, AntibodyCapture, MultiplexingCapture],
+                SampleBarcode::Cmo,
+            ),
+            (
+                SingleCell3PrimeV4,
+                vec![GeneExpression, AntibodyCapture],
+                SampleBarcode::Hashtag,
+            ),
+            (
+                SingleCell5PrimeV3,
+                vec![GeneExpression, AntibodyCapture, VdjT],
+                SampleBarcode::Hashtag,
+            ),
+        ] {
+            assert_eq!(
+                SampleBarcode::for_pool(chemistry, &library_types),
+                expected,
+                "{chemistry} {library_types:?}"
+            );
+        }
+    }
+}
@@ -1,30 +1,243 @@
-use std::fs;
+use std::{collections::HashMap, fs};
 
-use anyhow::Context;
-use camino::Utf8Path;
+use anyhow::{Context, bail};
+use camino::{Utf8Path, Utf8PathBuf};
+use csv::StringRecord;
 use itertools::Itertools;
 use serde::{Deserialize, de::DeserializeOwned};
 
+use row::RowError;
+
+use super::domain::{CellularMaterial, Chemistry, LibraryType, Species};
+use crate::{
+    cache::Cache,
+    xenium::client::{ApiError, GoogleSheetsClient},
+};
+
+mod row;
+
+/// Maps a column header to a map of alternative spellings of a value to the value as it should be parsed
+pub type Aliases = HashMap<String, HashMap<String, String>>;
+
+pub struct TrackingSheet {
+    pub suspensions: Vec<Suspension>,
+    pub multiplexed_suspensions: Vec<MultiplexedSuspension>,
+    pub gems: Vec<Gems>,
+    pub gems_suspensions: Vec<GemsSuspensions>,
+    pub libraries: Vec<Library>,
+    pub features: Vec<Feature>,
+}
+
+impl TrackingSheet {
+    pub fn from_dir(dir: &Utf8Path, aliases: &Aliases) -> anyhow::Result<Self> {
+        Self::from_results(
+            Suspension::from_tracking_sheet_dir(dir, aliases),
+            MultiplexedSuspension::from_tracking_sheet_dir(dir, aliases),
+            Gems::from_tracking_sheet_dir(dir, aliases),
+            GemsSuspensions::from_tracking_sheet_dir(dir, aliases),
+            Library::from_tracking_sheet_dir(dir, aliases),
+            Feature::from_tracking_sheet_dir(dir, aliases),
+        )
+    }
+
+    pub async fn download(
+        cache: &Cache,
+        client: &GoogleSheetsClient,
+        spreadsheet_id: &str,
+        aliases: &Aliases,
+    ) -> anyhow::Result<Self> {
+        let (suspensions, multiplexed_suspensions, gems, gems_suspensions, libraries, features) = futures::join!(
+            download_sheet(cache, client, spreadsheet_id, aliases),
+            download_sheet(cache, client, spreadsheet_id, aliases),
+            download_sheet(cache, client, spreadsheet_id, aliases),
+            download_sheet(cache, client, spreadsheet_id, aliases),
+            download_sheet(cache, client, spreadsheet_id, aliases),
+            download_sheet(cache, client, spreadsheet_id, aliases),
+        );
+
+        Self::from_results(
+            suspensions,
+            multiplexed_suspensions,
+            gems,
+            gems_suspensions,
+            libraries,
+            features,
+        )
+    }
+
+    // Whoever maintains the tracking sheet should be able to fix every problem in one pass, so report the errors from
+    // all sheets instead of stopping at the first one
+    fn from_results(
+        suspensions: anyhow::Result<Vec<Suspension>>,
+        multiplexed_suspensions: anyhow::Result<Vec<MultiplexedSuspension>>,
+        gems: anyhow::Result<Vec<Gems>>,
+        gems_suspensions: anyhow::Result<Vec<GemsSuspensions>>,
+        libraries: anyhow::Result<Vec<Library>>,
+        features: anyhow::Result<Vec<Feature>>,
+    ) -> anyhow::Result<Self> {
+        let errors: Vec<_> = [
+            suspensions.as_ref().err(),
+            multiplexed_suspensions.as_ref().err(),
+            gems.as_ref().err(),
+            gems_suspensions.as_ref().err(),
+            libraries.as_ref().err(),
+            features.as_ref().err(),
+        ]
+        .into_iter()
+        .flatten()
+        .map(|e| format!("{e:#}"))
+        .collect();
+
+        if !errors.is_empty() {
+            bail!("{}", errors.join("\n"));
+        }
+
+        Ok(Self {
+            suspensions: suspensions?,
+            multiplexed_suspensions: multiplexed_suspensions?,
+            gems: gems?,
+            gems_suspensions: gems_suspensions?,
+            libraries: libraries?,
+            features: features?,
+        })
+    }
+}
+
+// A successful response from the Google Sheets API always has a `range`, but leaves out the `values` field entirely for
+// an empty sheet. Requiring the former means that nothing else (like an error body) can pass for an empty sheet
+#[derive(Deserialize)]
+struct SheetValues {
+    #[serde(rename = "range")]
+    _range: String,
+    #[serde(default)]
+    values: Vec<Vec<String>>,
+}
+
+async fn download_sheet<T: FromTrackingSheetDir>(
+    cache: &Cache,
+    client: &GoogleSheetsClient,
+    spreadsheet_id: &str,
+    aliases: &Aliases,
+) -> anyhow::Result<Vec<T>> {
+    let sheet_name = T::sheet_name();
+
+    let downloaded = cache
+        .get_or_download(client, spreadsheet_id, &format!("'{sheet_name}'"))
+        .await;
+
+    let values = match downloaded {
+        Ok(SheetValues { values, .. }) => values,
+        Err(err) if T::is_optional() && is_missing_sheet(&err) => return Ok(Vec::new()),
+        Err(err) => return Err(err.context(format!("failed to download tracking sheet {sheet_name}"))),
+    };
+
+    T::from_rows(&values, aliases)
+}
+
+fn is_missing_sheet(err: &anyhow::Error) -> bool {
+    err.chain()
+        .any(|e| e.downcast_ref::<ApiError>().is_some_and(ApiError::is_missing_sheet))
+}
+
 pub trait FromTrackingSheetDir: Sized + DeserializeOwned {
     fn filename() -> &'static str;
 
-    fn from_tracking_sheet_dir(dir: &Utf8Path) -> anyhow::Result<Vec<Self>> {
+    /// The name of the tab in the Google Sheets workbook
+    fn sheet_name() -> &'static str;
+
+    fn from_tracking_sheet_dir(dir: &Utf8Path, aliases: &Aliases) -> anyhow::Result<Vec<Self>> {
         let path = dir.join(Self::filename());
-        let contents = fs::read_to_string(&path)
-            .context(format!("failed to read {path}"))?
-            .split('\n')
-            .skip(Self::header_row())
-            .join("\n");
+        if Self::is_optional() && !path.exists() {
+            return Ok(Vec::new());
+        }
+        let contents = fs::read_to_string(&path).context(format!("failed to read {path}"))?;
 
-        let mut reader = csv::Reader::from_reader(contents.as_bytes());
-        let records: anyhow::Result<Vec<_>, _> = reader.deserialize().collect();
+        Self::from_csv(&contents, Self::filename(), aliases)
+    }
+
+    fn from_rows(rows: &[Vec<String>], aliases: &Aliases) -> anyhow::Result<Vec<Self>> {
+        // The Google Sheets API drops trailing empty cells, so pad each row out to the same length
+        let n_cols = rows.iter().map(Vec::len).max().unwrap_or_default();
+
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        for row in rows {
+            writer.write_record(row.iter().map(String::as_str).pad_using(n_cols, |_| ""))?;
+        }
+
+        let contents = String::from_utf8(writer.into_inner()?)?;
+
+        Self::from_csv(&contents, Self::sheet_name(), aliases)
+    }
+
+    fn from_csv(contents: &str, source: &str, aliases: &Aliases) -> anyhow::Result<Vec<Self>> {
+        // Every CSV record is one spreadsheet row, so reading the header row as a record rather than skipping lines
+        // keeps row numbers in error messages matching the spreadsheet
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(contents.as_bytes());
+        let mut raw_records = reader.records().enumerate().skip(Self::header_row());
+
+        let Some((_, headers)) = raw_records.next() else {
+            if Self::is_optional() {
+                return Ok(Vec::new());
+            }
+
+            bail!("{source} is empty");
+        };
+        let headers = headers.context(format!("failed to read header row of {source}"))?;
+        let column_aliases: Vec<_> = headers.iter().map(|h| aliases.get(h)).collect();
+
+        let mut records = Vec::new();
+        let mut errors = Vec::new();
+        for (i, raw_record) in raw_records {
+            let row = i + 1;
+
+            let raw_record = match raw_record {
+                Ok(r) => r,
+                Err(e) => {
+                    errors.push(format!("{source}, row {row}: {e}"));
+                    continue;
+                }
+            };
+
+            if raw_record.iter().all(str::is_empty) {
+                continue;
+            }
+
+            // Replace aliases with their canonical values before deserializing, so that a value that is neither fails
+            // here with its position in the sheet
+            let record: StringRecord = raw_record
+                .iter()
+                .zip(column_aliases.iter().chain(std::iter::repeat(&None)))
+                .map(|(value, aliases)| aliases.and_then(|a| a.get(value)).map_or(value, String::as_str))
+                .collect();
 
-        Ok(records?)
+            match row::deserialize(&headers, &record) {
+                Ok(r) => records.push(r),
+                Err(RowError {
+                    column: Some(column),
+                    error,
+                }) => errors.push(format!("{source}, row {row}, column '{column}': {error}")),
+                Err(RowError { column: None, error }) => errors.push(format!("{source}, row {row}: {error}")),
+            }
+        }
+
+        if !errors.is_empty() {
+            bail!("{}", errors.join("\n"));
+        }
+
+        Ok(records)
     }
 
     fn header_row() -> usize {
         0
     }
+
+    /// Whether the sheet can be left out of the tracking sheet entirely, in which case it's treated as empty
+    fn is_optional() -> bool {
+        false
+    }
 }
 
 pub trait Id {
@@ -38,21 +251,27 @@ pub struct Suspension {
     #[serde(rename = "Specimen Name")]
     pub name: String,
     #[serde(rename = "Species")]
-    pub species: String,
+    pub species: Species,
     #[serde(rename = "Cellular Material")]
-    pub cellular_material: String,
+    pub cellular_material: CellularMaterial,
     #[serde(rename = "Tissue")]
     pub tissue: String,
     #[serde(rename = "Multiplexing Tag ID")]
     pub tag_id: Option<String>,
     #[serde(rename = "Pooled Into ID")]
     pub pooled_into_id: Option<String>,
+    #[serde(rename = "Lab Name")]
+    pub lab_name: Option<String>,
 }
 impl FromTrackingSheetDir for Suspension {
     fn filename() -> &'static str {
         "Chromium(Suspensions).csv"
     }
 
+    fn sheet_name() -> &'static str {
+        "Suspensions"
+    }
+
     fn header_row() -> usize {
         1
     }
@@ -75,6 +294,10 @@ impl FromTrackingSheetDir for MultiplexedSuspension {
         "Chromium(Multiplexed Suspensions).csv"
     }
 
+    fn sheet_name() -> &'static str {
+        "Multiplexed Suspensions"
+    }
+
     fn header_row() -> usize {
         1
     }
@@ -90,12 +313,19 @@ pub struct Gems {
     #[serde(rename = "GEMs ID")]
     id: String,
     #[serde(rename = "Chemistry")]
-    pub chemistry: String,
+    pub chemistry: Chemistry,
+    /// Replaces cellranger's default inner-enrichment primers for this GEMs' VDJ libraries
+    #[serde(rename = "Inner Enrichment Primers")]
+    pub inner_enrichment_primers: Option<Utf8PathBuf>,
 }
 impl FromTrackingSheetDir for Gems {
     fn filename() -> &'static str {
         "Chromium(GEMs).csv"
     }
+
+    fn sheet_name() -> &'static str {
+        "GEMs"
+    }
 }
 impl Id for Gems {
     fn id(&self) -> &str {
@@ -116,6 +346,10 @@ impl FromTrackingSheetDir for GemsSuspensions {
     fn filename() -> &'static str {
         "Chromium(GEMs-Suspensions).csv"
     }
+
+    fn sheet_name() -> &'static str {
+        "GEMs-Suspensions"
+    }
 }
 
 #[derive(Deserialize)]
@@ -125,15 +359,264 @@ pub struct Library {
     #[serde(rename = "GEMs ID")]
     pub gems_id: String,
     #[serde(rename = "Library Type")]
-    pub type_: String,
+    pub type_: LibraryType,
 }
 impl FromTrackingSheetDir for Library {
     fn filename() -> &'static str {
         "Chromium(Libraries).csv"
     }
+
+    fn sheet_name() -> &'static str {
+        "Libraries"
+    }
 }
 impl Id for Library {
     fn id(&self) -> &str {
         &self.id
     }
 }
+
+/// A row of a feature barcoding panel, which applies to either a GEMs or a suspension (and so to every GEMs it's loaded
+/// into)
+#[derive(Deserialize)]
+pub struct Feature {
+    #[serde(rename = "GEMs ID")]
+    pub gems_id: Option<String>,
+    #[serde(rename = "Suspension ID")]
+    pub suspension_id: Option<String>,
+    #[serde(rename = "Feature ID")]
+    pub id: String,
+    #[serde(rename = "Feature Name")]
+    pub name: String,
+    #[serde(rename = "Read")]
+    pub read: String,
+    #[serde(rename = "Pattern")]
+    pub pattern: String,
+    #[serde(rename = "Sequence")]
+    pub sequence: String,
+    #[serde(rename = "Feature Type")]
+    pub feature_type: LibraryType,
+    #[serde(rename = "Target Gene ID")]
+    pub target_gene_id: Option<String>,
+    #[serde(rename = "Target Gene Name")]
+    pub target_gene_name: Option<String>,
+}
+impl FromTrackingSheetDir for Feature {
+    fn filename() -> &'static str {
+        "Chromium(Feature Panels).csv"
+    }
+
+    fn sheet_name() -> &'static str {
+        "Feature Panels"
+    }
+
+    // Most projects have no feature barcoding, so the sheet doesn't have to exist
+    fn is_optional() -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    use super::*;
+    use crate::cache;
+
+    const SPREADSHEET_ID: &str = "tracking-sheet";
+
+    /// Serve `respond`'s answer for each requested sheet from a local listener standing in for the Google Sheets API,
+    /// returning its base URL
+    fn serve(respond: fn(&str) -> (u16, serde_json::Value)) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(&stream);
+
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                }
+
+                // GET /<SPREADSHEET ID>/values/'<SHEET NAME>'?majorDimension=ROWS HTTP/1.1
+                let path = request_line.split_whitespace().nth(1).unwrap();
+                let range = path.split_once("/values/").unwrap().1.split('?').next().unwrap();
+                let sheet_name = range.replace("%20", " ").replace("%27", "'");
+
+                let (status, body) = respond(sheet_name.trim_matches('\''));
+                let body = body.to_string();
+                write!(
+                    stream,
+                    "HTTP/1.1 {status} STATUS\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: \
+                     close\r\n\r\n{body}",
+                    body.len()
+                )
+                .unwrap();
+            }
+        });
+
+        format!("http://{addr}")
+    }
+
+    async fn download(base_url: &str) -> (tempfile::TempDir, anyhow::Result<TrackingSheet>) {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let cache = Cache::new(cache_dir.path().try_into().unwrap(), &cache::Config::default());
+        let client = GoogleSheetsClient::with_base_url("key", base_url).unwrap();
+
+        let tracking_sheet = TrackingSheet::download(&cache, &client, SPREADSHEET_ID, &Aliases::new()).await;
+
+        (cache_dir, tracking_sheet)
+    }
+
+    fn values(sheet_name: &str, rows: serde_json::Value) -> (u16, serde_json::Value) {
+        (
+            200,
+            json!({ "range": format!("'{sheet_name}'!A1:Z1000"), "majorDimension": "ROWS", "values": rows }),
+        )
+    }
+
+    fn error(code: u16, status: &str, message: &str) -> (u16, serde_json::Value) {
+        (
+            code,
+            json!({ "error": { "code": code, "message": message, "status": status } }),
+        )
+    }
+
+    #[tokio::test]
+    async fn downloads_every_sheet() {
+        let base_url = serve(|sheet_name| match sheet_name {
+            "Suspensions" => values(
+                sheet_name,
+                json!([
+                    ["Suspensions"],
+                    [
+                        "Suspension ID",
+                        "Specimen Name",
+                        "Species",
+                        "Cellular Material",
+                        "Tissue",
+                        "Multiplexing Tag ID",
+                        "Pooled Into ID",
+                        "Lab Name"
+                    ],
+                    ["S1", "mouse1", "Mus musculus", "Cells", "Brain"],
+                    ["S2", "human1", "Homo sapiens", "Nuclei", "Lung", "BC001", "P1", "Lab"]
+                ]),
+            ),
+            "Multiplexed Suspensions" => values(
+                sheet_name,
+                json!([
+                    ["Multiplexed Suspensions"],
+                    ["Multiplexed Suspension (Pool) ID", "Multiplexed Suspension (Pool) Name"],
+                    ["P1", "pool"]
+                ]),
+            ),
+            "GEMs" => values(
+                sheet_name,
+                json!([["GEMs ID", "Chemistry"], ["G1", "Single Cell 3' v4 (polyA)"]]),
+            ),
+            "GEMs-Suspensions" => values(
+                sheet_name,
+                json!([["GEMs ID", "Suspension ID", "Multiplexed Suspension ID"], ["G1", "S1"]]),
+            ),
+            "Libraries" => values(sheet_name, json!([["Library ID", "GEMs ID", "Library Type"]])),
+            _ => error(
+                400,
+                "INVALID_ARGUMENT",
+                &format!("Unable to parse range: '{sheet_name}'"),
+            ),
+        });
+
+        let (_cache_dir, tracking_sheet) = download(&base_url).await;
+        let TrackingSheet {
+            suspensions,
+            multiplexed_suspensions,
+            gems,
+            gems_suspensions,
+            libraries,
+            features,
+        } = tracking_sheet.unwrap();
+
+        assert_eq!(suspensions.iter().map(|s| s.id()).collect_vec(), ["S1", "S2"]);
+        assert_eq!(suspensions[0].tag_id, None);
+        assert_eq!(suspensions[1].pooled_into_id.as_deref(), Some("P1"));
+        assert_eq!(multiplexed_suspensions[0].name, "pool");
+        assert_eq!(gems[0].chemistry, Chemistry::SingleCell3PrimeV4);
+        assert_eq!(gems_suspensions[0].suspension_id.as_deref(), Some("S1"));
+        assert!(libraries.is_empty());
+        // The Feature Panels sheet is optional, so its absence isn't an error
+        assert!(features.is_empty());
+    }
+
+    #[tokio::test]
+    async fn reports_successfully_downloaded_empty_sheets() {
+        // A successful response for an empty sheet has no `values`
+        let base_url = serve(|sheet_name| {
+            (
+                200,
+                json!({ "range": format!("'{sheet_name}'!A1:Z1000"), "majorDimension": "ROWS" }),
+            )
+        });
+
+        let (_cache_dir, tracking_sheet) = download(&base_url).await;
+        let err = format!("{:#}", tracking_sheet.err().unwrap());
+
+        assert!(err.contains("Suspensions is empty"), "{err}");
+        assert!(!err.contains("Feature Panels"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn reports_api_errors() {
+        let base_url = serve(|_| error(403, "PERMISSION_DENIED", "The caller does not have permission"));
+
+        let (cache_dir, tracking_sheet) = download(&base_url).await;
+        let err = format!("{:#}", tracking_sheet.err().unwrap());
+
+        // Every sheet, including the optional one, fails with the API's explanation rather than as an empty sheet
+        assert!(!err.contains("is empty"), "{err}");
+        for sheet_name in [
+            "Suspensions",
+            "Multiplexed Suspensions",
+            "GEMs",
+            "GEMs-Suspensions",
+            "Libraries",
+            "Feature Panels",
+        ] {
+            assert!(
+                err.contains(&format!("failed to download tracking sheet {sheet_name}:")),
+                "{err}"
+            );
+        }
+        assert!(err.contains("403 Forbidden"), "{err}");
+        assert!(err.contains("The caller does not have permission"), "{err}");
+
+        // Nothing is cached, so the next run asks again
+        assert!(!cache_dir.path().join("google-sheets").exists());
+    }
+
+    #[tokio::test]
+    async fn does_not_cache_unusable_responses() {
+        let base_url = serve(|_| (200, json!({ "unexpected": "shape" })));
+
+        let (cache_dir, tracking_sheet) = download(&base_url).await;
+        let err = format!("{:#}", tracking_sheet.err().unwrap());
+
+        assert!(err.contains("failed to deserialize spreadsheet"), "{err}");
+        assert!(!cache_dir.path().join("google-sheets").exists());
+    }
+}
@@ -0,0 +1,116 @@
+use std::{cell::Cell, iter};
+
+use csv::StringRecord;
+use serde::{
+    Deserialize,
+    de::{
+        self, DeserializeSeed, Deserializer, MapAccess, Visitor,
+        value::{Error, StrDeserializer},
+    },
+    forward_to_deserialize_any,
+};
+
+// csv's own serde support doesn't say which column an error came from when the error is raised by the type being
+// deserialized into (e.g. an unknown enum variant), so rows are deserialized with this instead. Like csv, it treats an
+// empty cell as `None`. Cells missing from the end of a short row are treated as empty.
+
+pub(super) struct RowError<'a> {
+    pub(super) column: Option<&'a str>,
+    pub(super) error: Error,
+}
+
+pub(super) fn deserialize<'a, T: Deserialize<'a>>(
+    headers: &'a StringRecord,
+    record: &'a StringRecord,
+) -> Result<T, RowError<'a>> {
+    let current_column = Cell::new(None);
+
+    let row = Row {
+        cells: headers.iter().zip(record.iter().chain(iter::repeat(""))),
+        current_column: &current_column,
+        value: None,
+    };
+
+    T::deserialize(row).map_err(|error| RowError {
+        column: current_column.get(),
+        error,
+    })
+}
+
+struct Row<'a, 'b, I> {
+    cells: I,
+    current_column: &'b Cell<Option<&'a str>>,
+    value: Option<&'a str>,
+}
+
+impl<'de, I: Iterator<Item = (&'de str, &'de str)>> Deserializer<'de> for Row<'de, '_, I> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_map(self)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+impl<'de, I: Iterator<Item = (&'de str, &'de str)>> MapAccess<'de> for Row<'de, '_, I> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, Self::Error> {
+        let Some((header, value)) = self.cells.next() else {
+            self.current_column.set(None);
+            return Ok(None);
+        };
+
+        self.current_column.set(Some(header));
+        self.value = Some(value);
+
+        seed.deserialize(StrDeserializer::new(header)).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Self::Error> {
+        let value = self
+            .value
+            .take()
+            .ok_or_else(|| de::Error::custom("value requested before key"))?;
+
+        seed.deserialize(Value(value))
+    }
+}
+
+struct Value<'a>(&'a str);
+
+impl<'de> Deserializer<'de> for Value<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_borrowed_str(self.0)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        if self.0.is_empty() {
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_enum(StrDeserializer::new(self.0))
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
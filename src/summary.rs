@@ -0,0 +1,298 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+};
+
+use anyhow::{Context, ensure};
+use camino::{Utf8Path, Utf8PathBuf};
+use itertools::Itertools;
+use metrics::{SampleMetrics, find_sample_metrics};
+
+use crate::{
+    cache::Cache,
+    samplesheet::{
+        self,
+        tracking_sheet::{Gems, Id, Library, Suspension, TrackingSheet},
+    },
+};
+
+mod metrics;
+
+/// The name of the summary for metrics whose lab couldn't be determined
+const UNKNOWN_LAB: &str = "unknown_lab";
+const ID_COLUMNS: [&str; 12] = [
+    "Metrics Path",
+    "Pipeline",
+    "Per-Sample Name",
+    "Library IDs",
+    "Library Types",
+    "GEMs IDs",
+    "Chemistry",
+    "Suspension IDs",
+    "Specimen Names",
+    "Species",
+    "Tissue",
+    "Lab Name",
+];
+
+pub(super) async fn write(
+    config: &samplesheet::config::Config,
+    cache: &Cache,
+    nf_tenx_dirs: &[Utf8PathBuf],
+    tracking_sheet_dir: &Utf8Path,
+    fetch_tracking_sheet: bool,
+    output_dir: &Utf8Path,
+) -> anyhow::Result<()> {
+    let tracking_sheet =
+        samplesheet::load_tracking_sheet(config, cache, tracking_sheet_dir, fetch_tracking_sheet).await?;
+    let index = TrackingSheetIndex::new(&tracking_sheet);
+
+    let mut sample_metrics = Vec::new();
+    for dir in nf_tenx_dirs {
+        ensure!(
+            dir.is_dir(),
+            "nf-tenx output paths must be directories, but {dir} is not"
+        );
+
+        sample_metrics.extend(find_sample_metrics(dir).context(format!("failed to read metrics from {dir}"))?);
+    }
+
+    ensure!(
+        !sample_metrics.is_empty(),
+        "found no metrics_summary.csv files in the given nf-tenx output directories"
+    );
+
+    let rows = sample_metrics.iter().map(|m| index.summary_row(m)).collect_vec();
+
+    // These metrics are still written, but whoever ran this should know that they're missing information
+    for SummaryRow {
+        sample_metrics,
+        libraries,
+        lab_name,
+        ..
+    } in &rows
+    {
+        let path = &sample_metrics.path;
+
+        if libraries.is_empty() {
+            eprintln!(
+                "no library in the tracking sheet matches {path}, so it was written to {UNKNOWN_LAB}_metrics.csv with \
+                 no tracking sheet information"
+            );
+        } else if lab_name.is_none() {
+            eprintln!("no lab name for the suspensions in {path}, so it was written to {UNKNOWN_LAB}_metrics.csv");
+        }
+    }
+
+    let rows_grouped_by_lab = rows.into_iter().into_group_map_by(|r| r.lab_name.clone());
+
+    fs::create_dir_all(output_dir).context(format!("failed to create {output_dir}"))?;
+
+    for (lab_name, rows) in rows_grouped_by_lab {
+        let filename = lab_name.as_deref().map(slugify).unwrap_or(UNKNOWN_LAB.to_string());
+        let path = output_dir.join(format!("{filename}_metrics.csv"));
+
+        write_summary_csv(&path, &rows).context(format!("failed to write summary metrics to {path}"))?;
+        println!("wrote metrics for {} samples to {path}", rows.len());
+    }
+
+    Ok(())
+}
+
+struct TrackingSheetIndex<'a> {
+    libraries: HashMap<&'a str, &'a Library>,
+    gems: HashMap<&'a str, &'a Gems>,
+    suspensions: HashMap<&'a str, &'a Suspension>,
+    suspensions_grouped_by_pool: HashMap<&'a str, Vec<&'a Suspension>>,
+    suspension_ids_grouped_by_gems_id: HashMap<&'a str, Vec<&'a str>>,
+    multiplexed_suspension_ids_grouped_by_gems_id: HashMap<&'a str, Vec<&'a str>>,
+}
+
+impl<'a> TrackingSheetIndex<'a> {
+    fn new(tracking_sheet: &'a TrackingSheet) -> Self {
+        let TrackingSheet {
+            suspensions,
+            gems,
+            gems_suspensions,
+            libraries,
+            ..
+        } = tracking_sheet;
+
+        Self {
+            libraries: libraries.iter().map(|l| (l.id(), l)).collect(),
+            gems: gems.iter().map(|g| (g.id(), g)).collect(),
+            suspensions: suspensions.iter().map(|s| (s.id(), s)).collect(),
+            suspensions_grouped_by_pool: suspensions
+                .iter()
+                .filter_map(|s| s.pooled_into_id.as_ref().map(|m| (m.as_str(), s)))
+                .into_group_map(),
+            suspension_ids_grouped_by_gems_id: gems_suspensions
+                .iter()
+                .filter_map(|gs| gs.suspension_id.as_ref().map(|s| (gs.gems_id.as_str(), s.as_str())))
+                .into_group_map(),
+            multiplexed_suspension_ids_grouped_by_gems_id: gems_suspensions
+                .iter()
+                .filter_map(|gs| {
+                    gs.multiplexed_suspension_id
+                        .as_ref()
+                        .map(|s| (gs.gems_id.as_str(), s.as_str()))
+                })
+                .into_group_map(),
+        }
+    }
+
+    // nf-tenx names its output directories after the libraries that went into each sample, so any path component
+    // (or '_'-separated part of one) that is a library ID identifies the sample
+    fn matching_libraries(&self, sample_metrics: &SampleMetrics) -> Vec<&'a Library> {
+        sample_metrics
+            .path_components
+            .iter()
+            .flat_map(|c| std::iter::once(c.as_str()).chain(c.split('_')))
+            .filter_map(|c| self.libraries.get(c).copied())
+            .unique_by(|l| l.id())
+            .collect()
+    }
+
+    fn suspensions_for_gems(&self, gems_id: &str) -> Vec<&'a Suspension> {
+        let direct = self
+            .suspension_ids_grouped_by_gems_id
+            .get(gems_id)
+            .into_iter()
+            .flatten()
+            .filter_map(|id| self.suspensions.get(id).copied());
+
+        let pooled = self
+            .multiplexed_suspension_ids_grouped_by_gems_id
+            .get(gems_id)
+            .into_iter()
+            .flatten()
+            .filter_map(|id| self.suspensions_grouped_by_pool.get(id))
+            .flatten()
+            .copied();
+
+        direct.chain(pooled).collect()
+    }
+
+    fn summary_row<'b>(&self, sample_metrics: &'b SampleMetrics) -> SummaryRow<'a, 'b> {
+        let libraries = self.matching_libraries(sample_metrics);
+
+        let gems: Vec<_> = libraries
+            .iter()
+            .map(|l| l.gems_id.as_str())
+            .unique()
+            .filter_map(|id| self.gems.get(id).copied())
+            .collect();
+
+        let mut suspensions: Vec<_> = gems.iter().flat_map(|g| self.suspensions_for_gems(g.id())).collect();
+
+        // For a multiplexed sample, only the suspension that this per-sample output corresponds to is relevant
+        if let Some(per_sample_name) = &sample_metrics.per_sample_name {
+            let matching: Vec<_> = suspensions
+                .iter()
+                .copied()
+                .filter(|s| &s.name == per_sample_name || s.tag_id.as_ref() == Some(per_sample_name))
+                .collect();
+
+            if !matching.is_empty() {
+                suspensions = matching;
+            }
+        }
+
+        let lab_names: HashSet<_> = suspensions.iter().filter_map(|s| s.lab_name.as_deref()).collect();
+        let lab_name = (!lab_names.is_empty()).then(|| lab_names.into_iter().sorted().join("; "));
+
+        SummaryRow {
+            sample_metrics,
+            libraries,
+            gems,
+            suspensions,
+            lab_name,
+        }
+    }
+}
+
+struct SummaryRow<'a, 'b> {
+    sample_metrics: &'b SampleMetrics,
+    libraries: Vec<&'a Library>,
+    gems: Vec<&'a Gems>,
+    suspensions: Vec<&'a Suspension>,
+    lab_name: Option<String>,
+}
+
+impl SummaryRow<'_, '_> {
+    fn id_fields(&self) -> [String; ID_COLUMNS.len()] {
+        let Self {
+            sample_metrics,
+            libraries,
+            gems,
+            suspensions,
+            lab_name,
+        } = self;
+
+        let join = |values: Vec<&str>| values.into_iter().unique().join("; ");
+
+        [
+            sample_metrics.path.to_string(),
+            sample_metrics.pipeline.as_str().to_string(),
+            sample_metrics.per_sample_name.clone().unwrap_or_default(),
+            join(libraries.iter().map(|l| l.id.as_str()).collect()),
+            join(libraries.iter().map(|l| l.type_.as_str()).collect()),
+            join(gems.iter().map(|g| g.id()).collect()),
+            join(gems.iter().map(|g| g.chemistry.as_str()).collect()),
+            join(suspensions.iter().map(|s| s.id.as_str()).collect()),
+            join(suspensions.iter().map(|s| s.name.as_str()).collect()),
+            join(suspensions.iter().map(|s| s.species.as_str()).collect()),
+            join(suspensions.iter().map(|s| s.tissue.as_str()).collect()),
+            lab_name.clone().unwrap_or_default(),
+        ]
+    }
+}
+
+fn write_summary_csv(path: &Utf8Path, rows: &[SummaryRow]) -> anyhow::Result<()> {
+    // count and multi (and different chemistries) report different metrics, so the columns are the union of all of
+    // them in the order they first appear
+    let metric_names: Vec<_> = rows
+        .iter()
+        .flat_map(|r| r.sample_metrics.metrics.iter().map(|(name, _)| name.as_str()))
+        .unique()
+        .collect();
+
+    let mut writer = csv::Writer::from_path(path)?;
+    writer.write_record(ID_COLUMNS.iter().copied().chain(metric_names.iter().copied()))?;
+
+    for row in rows {
+        let metrics: HashMap<_, _> = row
+            .sample_metrics
+            .metrics
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect();
+
+        let id_fields = row.id_fields();
+        let metric_values = metric_names
+            .iter()
+            .map(|name| metrics.get(name).copied().unwrap_or_default());
+
+        writer.write_record(id_fields.iter().map(String::as_str).chain(metric_values))?;
+    }
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn slugify(lab_name: &str) -> String {
+    let lab_name = lab_name.trim();
+    let lab_name = lab_name.strip_suffix(" Lab").unwrap_or(lab_name);
+
+    lab_name
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,195 @@
+use std::fs;
+
+use anyhow::{Context, anyhow, ensure};
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::Deserialize;
+
+const METRICS_FILENAME: &str = "metrics_summary.csv";
+
+// cellranger count and cellranger multi lay out their metrics differently:
+// count
+// └── <SAMPLE>
+//     └── outs
+//         └── metrics_summary.csv   (one header row and one value row)
+//
+// multi
+// └── <SAMPLE>
+//     └── outs
+//         └── per_sample_outs
+//             └── <PER-SAMPLE NAME>
+//                 └── metrics_summary.csv   (one row per metric)
+
+#[derive(Debug, Clone, Copy)]
+pub(super) enum Pipeline {
+    Count,
+    Multi,
+}
+
+impl Pipeline {
+    pub(super) fn as_str(&self) -> &'static str {
+        match self {
+            Self::Count => "count",
+            Self::Multi => "multi",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(super) struct SampleMetrics {
+    pub(super) path: Utf8PathBuf,
+    pub(super) pipeline: Pipeline,
+    /// The path components between the root of the nf-tenx output tree and the metrics file
+    pub(super) path_components: Vec<String>,
+    /// For `cellranger multi`, the name of the sample within the multiplexed pool
+    pub(super) per_sample_name: Option<String>,
+    pub(super) metrics: Vec<(String, String)>,
+}
+
+impl SampleMetrics {
+    fn from_path(root: &Utf8Path, path: Utf8PathBuf) -> anyhow::Result<Self> {
+        let relative_path = path.strip_prefix(root).unwrap_or(&path);
+        let path_components: Vec<_> = relative_path
+            .parent()
+            .map(|p| p.components().map(|c| c.to_string()).collect())
+            .unwrap_or_default();
+
+        let per_sample_outs_idx = path_components.iter().position(|c| c == "per_sample_outs");
+
+        let contents = fs::read_to_string(&path).context(format!("failed to read {path}"))?;
+
+        let (pipeline, per_sample_name, metrics) = match per_sample_outs_idx {
+            Some(idx) => {
+                let per_sample_name = path_components
+                    .get(idx + 1)
+                    .ok_or(anyhow!("failed to get per-sample name from {path}"))?
+                    .clone();
+
+                (Pipeline::Multi, Some(per_sample_name), parse_multi_metrics(&contents)?)
+            }
+            None => (Pipeline::Count, None, parse_count_metrics(&contents)?),
+        };
+
+        Ok(Self {
+            path,
+            pipeline,
+            path_components,
+            per_sample_name,
+            metrics,
+        })
+    }
+}
+
+pub(super) fn find_sample_metrics(root: &Utf8Path) -> anyhow::Result<Vec<SampleMetrics>> {
+    let mut metrics_paths = Vec::new();
+    collect_metrics_paths(root, &mut metrics_paths)?;
+    metrics_paths.sort();
+
+    metrics_paths
+        .into_iter()
+        .map(|p| SampleMetrics::from_path(root, p))
+        .collect()
+}
+
+fn collect_metrics_paths(dir: &Utf8Path, metrics_paths: &mut Vec<Utf8PathBuf>) -> anyhow::Result<()> {
+    for entry in dir.read_dir_utf8().context(format!("failed to read {dir}"))? {
+        let entry = entry?;
+
+        // Symbolic links to directories aren't followed, as they'd count the same metrics twice (or never finish, if
+        // they form a cycle). Symbolic links to metrics files are kept, since that's how Nextflow publishes outputs by
+        // default
+        if entry.file_type()?.is_dir() {
+            collect_metrics_paths(entry.path(), metrics_paths)?;
+        } else if entry.file_name() == METRICS_FILENAME {
+            metrics_paths.push(entry.into_path());
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_count_metrics(contents: &str) -> anyhow::Result<Vec<(String, String)>> {
+    let mut reader = csv::Reader::from_reader(contents.as_bytes());
+
+    let headers = reader.headers()?.clone();
+    let mut records = reader.records();
+
+    let values = records.next().ok_or(anyhow!("metrics file has no values"))??;
+    ensure!(records.next().is_none(), "expected exactly one row of metrics");
+
+    Ok(headers
+        .iter()
+        .zip(values.iter())
+        .map(|(h, v)| (h.to_string(), v.to_string()))
+        .collect())
+}
+
+#[derive(Deserialize)]
+struct MultiMetric {
+    #[serde(rename = "Category")]
+    category: String,
+    #[serde(rename = "Library Type")]
+    library_type: String,
+    #[serde(rename = "Group Name")]
+    group_name: Option<String>,
+    #[serde(rename = "Metric Name")]
+    metric_name: String,
+    #[serde(rename = "Metric Value")]
+    metric_value: String,
+}
+
+fn parse_multi_metrics(contents: &str) -> anyhow::Result<Vec<(String, String)>> {
+    let mut reader = csv::Reader::from_reader(contents.as_bytes());
+
+    let mut metrics = Vec::new();
+    for record in reader.deserialize() {
+        let MultiMetric {
+            category,
+            library_type,
+            group_name,
+            metric_name,
+            metric_value,
+        } = record?;
+
+        // The same metric name shows up for several categories, library types, and groups (e.g. per probe barcode),
+        // so all of them go into the column name
+        let name = match group_name {
+            Some(group_name) => format!("{category} - {library_type} - {metric_name} ({group_name})"),
+            None => format!("{category} - {library_type} - {metric_name}"),
+        };
+
+        metrics.push((name, metric_value));
+    }
+
+    Ok(metrics)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::symlink};
+
+    use pretty_assertions::assert_eq;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn does_not_follow_symlinked_directories() {
+        let dir = TempDir::new().unwrap();
+        let root = Utf8PathBuf::from_path_buf(dir.path().to_path_buf()).unwrap();
+
+        let work_dir = root.join("work/ab/cdef");
+        fs::create_dir_all(&work_dir).unwrap();
+        fs::write(work_dir.join(METRICS_FILENAME), "").unwrap();
+
+        let sample_dir = root.join("results/sample");
+        fs::create_dir_all(&sample_dir).unwrap();
+        symlink(work_dir.join(METRICS_FILENAME), sample_dir.join(METRICS_FILENAME)).unwrap();
+        symlink(&work_dir, root.join("results/linked-work")).unwrap();
+        symlink(&root, sample_dir.join("cycle")).unwrap();
+
+        let mut metrics_paths = Vec::new();
+        collect_metrics_paths(&root.join("results"), &mut metrics_paths).unwrap();
+
+        assert_eq!(metrics_paths, [sample_dir.join(METRICS_FILENAME)]);
+    }
+}
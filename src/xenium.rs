@@ -1,67 +1,319 @@
-mod client;
+pub(crate) mod client;
 pub(super) mod config;
+mod design;
 mod dir;
+mod experiment;
+mod manifest;
+pub(super) mod plan;
+mod progress;
+mod rerun;
 mod slide;
 mod spreadsheet;
+mod transfer;
 
-use anyhow::Context;
-use camino::Utf8PathBuf;
+use std::{collections::HashMap, pin::pin, sync::Arc};
+
+use anyhow::{Context, ensure};
+use camino::{Utf8Path, Utf8PathBuf};
 use client::GoogleSheetsClient;
 use config::{Config, SpreadsheetSpecification};
 use console::Term;
 use dir::{ParsedDataDir, confirm_move};
-use itertools::Itertools;
+use futures::StreamExt;
+use itertools::{Either, Itertools};
+use plan::{Action, CollisionPolicy, Plan, PlannedMove};
+use progress::{Progress, SlideProgress};
+use rerun::RerunDir;
+use spreadsheet::ValueRange;
+
+use crate::cache::Cache;
 
-const N_FIELDS: usize = 4;
+/// How `stage-xenium` should carry out its moves
+pub struct StagingOptions<'a> {
+    /// Move without asking for confirmation first
+    pub skip_confirm: bool,
+    /// Only print the plan
+    pub dry_run: bool,
+    /// Where to write the plan as JSON
+    pub plan_path: Option<&'a Utf8Path>,
+    pub collision_policy: CollisionPolicy,
+    /// The maximum number of slides to move at once
+    pub max_parallel_moves: usize,
+    /// The directories are the outputs of xeniumranger reruns rather than of the instrument
+    pub reruns: bool,
+    /// A local copy of the spreadsheet to read instead of downloading it
+    pub spreadsheet_file: Option<&'a Utf8Path>,
+}
 
-pub async fn stage_data(config: &config::Config, data_dirs: &[Utf8PathBuf], skip_confirm: bool) -> anyhow::Result<()> {
+pub async fn stage_data(
+    config: &config::Config,
+    cache: &Cache,
+    data_dirs: &[Utf8PathBuf],
+    options: &StagingOptions<'_>,
+) -> anyhow::Result<()> {
     let Config {
-        google_sheets_api_key,
         spreadsheet_spec,
         staging_dir_spec,
+        ..
     } = config;
 
-    let parsed_data_dirs: Vec<_> = data_dirs
-        .iter()
-        .map(ParsedDataDir::from_dir)
-        .try_collect()
-        .context("failed to parse Xenium data directory")?;
+    let StagingOptions {
+        skip_confirm,
+        dry_run,
+        plan_path,
+        collision_policy,
+        max_parallel_moves,
+        reruns,
+        spreadsheet_file,
+    } = *options;
 
-    let client = GoogleSheetsClient::new(google_sheets_api_key).context("failed to create Google Sheets client")?;
+    ensure!(
+        max_parallel_moves > 0,
+        "the maximum number of parallel moves must be at least 1"
+    );
 
-    let data = client
-        .download_spreadsheet(spreadsheet_spec)
-        .await
-        .context("failed to download Xenium spreadsheet")?;
+    let (parsed_data_dirs, rerun_dirs, interrupted_removals): (Vec<_>, Vec<_>, Vec<_>) = if reruns {
+        // An output that was copied, but not completely removed before the move was interrupted, only needs removing
+        let (interrupted_removals, remaining_dirs): (Vec<_>, Vec<_>) = data_dirs.iter().partition_map(|dir| {
+            let removal_path = transfer::removal_path(dir);
+            if !dir.exists() && removal_path.exists() {
+                Either::Left(removal_path)
+            } else {
+                Either::Right(dir)
+            }
+        });
+
+        let rerun_dirs = remaining_dirs
+            .into_iter()
+            .map(RerunDir::from_dir)
+            .try_collect()
+            .context("failed to parse xeniumranger output directory")?;
+
+        (Vec::new(), rerun_dirs, interrupted_removals)
+    } else {
+        let parsed_data_dirs: Vec<ParsedDataDir> = data_dirs
+            .iter()
+            .map(ParsedDataDir::from_dir)
+            .try_collect()
+            .context("failed to parse Xenium data directory")?;
+        let interrupted_removals = parsed_data_dirs
+            .iter()
+            .flat_map(|d| d.interrupted_removals.iter().cloned())
+            .collect();
+
+        (parsed_data_dirs, Vec::new(), interrupted_removals)
+    };
+
+    let data = match spreadsheet_file {
+        Some(path) => ValueRange::from_file(path, &spreadsheet_spec.range)
+            .context(format!("failed to read Xenium spreadsheet from {path}"))?,
+        None => download_spreadsheet(config, cache).await?,
+    };
 
     let xenium_slides = data
         .to_xenium_slides(spreadsheet_spec)
         .context("failed to parse spreadsheet into Xenium slides")?;
 
-    let renamings: Vec<_> = parsed_data_dirs
-        .iter()
-        .map(|d| {
-            d.construct_new_subdir_names(&xenium_slides, staging_dir_spec)
-                .context("failed to construct new data directory names")
-        })
-        .try_collect()?;
+    let moves: Vec<_> = if reruns {
+        rerun::plan_moves(&rerun_dirs, &xenium_slides, staging_dir_spec, collision_policy)?
+    } else {
+        parsed_data_dirs
+            .iter()
+            .map(|d| {
+                d.construct_new_subdir_names(&xenium_slides, staging_dir_spec, collision_policy)
+                    .context("failed to construct new data directory names")
+            })
+            .flatten_ok()
+            .try_collect()?
+    };
+    let plan = Plan { moves };
+
+    if let Some(plan_path) = plan_path {
+        plan.write_json(plan_path)?;
+    }
+
+    if dry_run {
+        plan.print();
+        for removal_path in &interrupted_removals {
+            println!("{removal_path} was already moved, so the rest of it would be removed");
+        }
+        return Ok(());
+    }
+
+    plan.ensure_no_failures()?;
 
-    let mut move_futures = Vec::new();
     let term = Term::stdout();
-    for renaming in &renamings {
-        for (old_path, new_path) in renaming {
-            let mut push_future = || move_futures.push(dir::rename(old_path, new_path));
-
-            if skip_confirm {
-                push_future();
-            } else if confirm_move(&term, old_path, new_path)? {
-                push_future();
-                term.write_line("")?;
+    for removal_path in &interrupted_removals {
+        transfer::finish_removal(removal_path)?;
+        term.write_line(&format!(
+            "{removal_path} was already moved, so the rest of it was removed"
+        ))?;
+    }
+
+    let mut confirmed_moves = Vec::new();
+    for planned_move in &plan.moves {
+        let PlannedMove {
+            source,
+            destination,
+            action,
+            ..
+        } = planned_move;
+
+        if *action == Action::Skip {
+            term.write_line(&format!(
+                "skipping moving {source} to {destination}, as it already exists"
+            ))?;
+            continue;
+        }
+
+        if skip_confirm || confirm_move(&term, planned_move)? {
+            let progress = Arc::new(SlideProgress::new(destination.to_string()));
+
+            confirmed_moves.push((planned_move, progress));
+        }
+
+        if !skip_confirm {
+            term.write_line("")?;
+        }
+    }
+
+    let progress = Progress::new(confirmed_moves.iter().map(|(_, p)| p.clone()).collect());
+    let move_futures = confirmed_moves
+        .iter()
+        .enumerate()
+        .map(|(i, (planned_move, progress))| async move {
+            let result = dir::rename(planned_move, progress.clone()).await;
+            progress.finish(result.is_ok());
+
+            (i, result)
+        });
+    let mut moves = pin!(
+        futures::stream::iter(move_futures)
+            .buffer_unordered(max_parallel_moves)
+            .collect::<Vec<_>>()
+    );
+
+    // Moves can take hours, so keep whoever is watching informed
+    let mut ticker = tokio::time::interval(Progress::interval(&term));
+    let results = loop {
+        tokio::select! {
+            results = &mut moves => break results,
+            _ = ticker.tick() => progress.draw(&term)?,
+        }
+    };
+    let results = results.into_iter().sorted_by_key(|(i, _)| *i).map(|(_, r)| r);
+    progress.draw(&term)?;
+
+    // One failed move shouldn't stop the others, so every failure is reported at the end
+    let mut n_failed = 0;
+    let mut checksums_by_slide_dir: HashMap<&Utf8Path, Option<HashMap<_, _>>> = HashMap::new();
+    for ((planned_move, _), result) in confirmed_moves.iter().zip(results) {
+        let PlannedMove {
+            source,
+            slide_dir,
+            destination,
+            ..
+        } = planned_move;
+        // The checksums of what was already in a rerun's slide directory don't need computing again, apart from those
+        // of the destination, which may have been overwritten or merged into. If the manifest can't be read, none of
+        // them can be trusted, so everything is checksummed again
+        let slide_checksums = checksums_by_slide_dir.entry(slide_dir).or_insert_with(|| {
+            let known_checksums = if reruns {
+                manifest::read(slide_dir).unwrap_or_else(|e| {
+                    eprintln!("{e:#}, so every file in {slide_dir} will be checksummed again");
+                    Default::default()
+                })
+            } else {
+                Default::default()
+            };
+
+            Some(
+                known_checksums
+                    .into_iter()
+                    .map(|(p, c)| (slide_dir.join(p), c))
+                    .collect(),
+            )
+        });
+
+        match result {
+            Ok(checksums) => {
+                term.write_line(&format!("moved {source} to {destination}"))?;
+                if let Some(slide_checksums) = slide_checksums {
+                    slide_checksums.retain(|path, _| !path.starts_with(destination));
+                    slide_checksums.extend(checksums);
+                }
+            }
+            Err(e) => {
+                n_failed += 1;
+                term.write_line(&format!("failed to move {source} to {destination}: {e:#}"))?;
+                *slide_checksums = None;
             }
         }
     }
 
-    futures::future::try_join_all(move_futures).await?;
+    // A slide's manifest can only be written once all of its regions have been moved
+    let mut n_manifests_failed = 0;
+    for (slide_dir, checksums) in checksums_by_slide_dir.into_iter().sorted_by_key(|(d, _)| *d) {
+        let Some(checksums) = checksums else {
+            continue;
+        };
+
+        let slide_dir = slide_dir.to_path_buf();
+        let result = tokio::task::spawn_blocking(move || manifest::write(&slide_dir, &checksums)).await?;
+        if let Err(e) = result {
+            n_manifests_failed += 1;
+            term.write_line(&format!("{e:#}"))?;
+        }
+    }
+
+    ensure!(n_failed == 0, "{n_failed} of {} moves failed", confirmed_moves.len());
+    ensure!(
+        n_manifests_failed == 0,
+        "failed to write {n_manifests_failed} checksum manifests"
+    );
+
+    Ok(())
+}
+
+async fn download_spreadsheet(config: &Config, cache: &Cache) -> anyhow::Result<ValueRange> {
+    let Config {
+        google_sheets_api_key,
+        spreadsheet_spec: SpreadsheetSpecification { id, range, .. },
+        ..
+    } = config;
+
+    let client = GoogleSheetsClient::new(google_sheets_api_key).context("failed to create Google Sheets client")?;
+
+    cache
+        .get_or_download(&client, id, range)
+        .await
+        .context("failed to download Xenium spreadsheet")
+}
+
+/// Check each staged slide directory against the manifest written when it was staged
+pub fn verify(slide_dirs: &[Utf8PathBuf]) -> anyhow::Result<()> {
+    let mut n_failed = 0;
+    for slide_dir in slide_dirs {
+        match manifest::verify(slide_dir) {
+            Ok(n_files) => println!("{slide_dir}: {n_files} files OK"),
+            Err(e) => {
+                eprintln!("{e:#}");
+                n_failed += 1;
+            }
+        }
+    }
+
+    ensure!(
+        n_failed == 0,
+        "{n_failed} of {} slide directories failed verification",
+        slide_dirs.len()
+    );
+
+    Ok(())
+}
+
+pub async fn refresh_cache(config: &Config, cache: &Cache) -> anyhow::Result<()> {
+    download_spreadsheet(config, cache).await?;
 
     Ok(())
 }
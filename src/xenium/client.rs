@@ -1,19 +1,30 @@
+use std::fmt::Display;
+
 use anyhow::Context;
 use reqwest::{
-    Client, ClientBuilder,
+    Client, ClientBuilder, StatusCode, Url,
     header::{HeaderMap, HeaderValue},
 };
+use serde::de::DeserializeOwned;
 
-use super::{
-    config::SpreadsheetSpecification,
-    spreadsheet::{self, MajorDimension},
-};
+use super::spreadsheet::MajorDimension;
+
+const GOOGLE_SHEETS_API_URL: &str = "https://sheets.googleapis.com/v4/spreadsheets";
 
 #[derive(Clone)]
-pub(super) struct GoogleSheetsClient(Client);
+pub(crate) struct GoogleSheetsClient {
+    client: Client,
+    base_url: String,
+}
 
 impl GoogleSheetsClient {
     pub fn new(api_key: &str) -> anyhow::Result<Self> {
+        Self::with_base_url(api_key, GOOGLE_SHEETS_API_URL)
+    }
+
+    /// Construct a client that talks to a Google Sheets API (or a stand-in for it) at `base_url` instead of the real
+    /// one
+    pub fn with_base_url(api_key: &str, base_url: &str) -> anyhow::Result<Self> {
         let mut api_key = HeaderValue::from_str(api_key)?;
         api_key.set_sensitive(true);
 
@@ -24,23 +35,30 @@ impl GoogleSheetsClient {
 
         let client = ClientBuilder::new().default_headers(headers).build()?;
 
-        Ok(Self(client))
+        Ok(Self {
+            client,
+            base_url: base_url.trim_end_matches('/').to_string(),
+        })
     }
 
-    pub async fn download_spreadsheet(
-        &self,
-        spec: &SpreadsheetSpecification,
-    ) -> anyhow::Result<spreadsheet::ValueRange> {
-        let Self(client) = self;
+    pub async fn download_range<T: DeserializeOwned>(&self, spreadsheet_id: &str, range: &str) -> anyhow::Result<T> {
+        let Self { client, base_url } = self;
 
-        let url = spec
-            .to_url()
-            .context("failed to construct URL from spreadsheet specification")?;
+        let url = Url::parse(&format!("{base_url}/{spreadsheet_id}/values/{range}"))
+            .context("failed to construct URL from spreadsheet ID and range")?;
         let request = client
             .get(url)
             .query(&[("majorDimension", &MajorDimension::Rows.to_string())]);
 
-        let raw_data = request.send().await?.text().await?;
+        let response = request.send().await?;
+        let status = response.status();
+        let raw_data = response.text().await?;
+
+        // Google explains what went wrong (a bad API key, an exceeded quota, a sheet that doesn't exist) in the body
+        // of an error response, which would otherwise be mistaken for data
+        if !status.is_success() {
+            return Err(ApiError { status, body: raw_data }.into());
+        }
 
         let data =
             serde_json::from_str(&raw_data).context(format!("failed to deserialize spreadsheet:\n{raw_data}"))?;
@@ -48,3 +66,26 @@ impl GoogleSheetsClient {
         Ok(data)
     }
 }
+
+/// An error response from the Google Sheets API
+#[derive(Debug)]
+pub(crate) struct ApiError {
+    status: StatusCode,
+    body: String,
+}
+
+impl ApiError {
+    /// Whether the request was for a sheet that doesn't exist, which Google reports as a range it can't parse
+    pub(crate) fn is_missing_sheet(&self) -> bool {
+        self.status == StatusCode::BAD_REQUEST && self.body.contains("Unable to parse range")
+    }
+}
+
+impl Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self { status, body } = self;
+        write!(f, "Google Sheets API responded with {status}:\n{body}")
+    }
+}
+
+impl std::error::Error for ApiError {}
@@ -1,8 +1,7 @@
-use std::{collections::HashMap, str::FromStr};
+use std::collections::HashMap;
 
 use anyhow::anyhow;
 use camino::Utf8PathBuf;
-use reqwest::Url;
 use serde::Deserialize;
 
 #[derive(Deserialize)]
@@ -12,23 +11,94 @@ pub struct Config {
     pub(super) staging_dir_spec: StagingDirSpecification,
 }
 
+// Each column can be found by its header, by its index, or both, in which case the index is only used if the header
+// isn't found. If any column is given by header, the first row of `range` must be the header row.
 #[derive(Deserialize)]
 pub(super) struct SpreadsheetSpecification {
-    id: String,
-    range: String,
-    pub(super) slide_id_col_idx: u8,
-    pub(super) slide_name_col_idx: u8,
-    pub(super) run_id_col_idx: u8,
-    pub(super) lab_name_col_idx: u8,
+    pub(super) id: String,
+    pub(super) range: String,
+    #[serde(default)]
+    slide_id_col_name: Option<String>,
+    #[serde(default)]
+    slide_name_col_name: Option<String>,
+    #[serde(default)]
+    run_id_col_name: Option<String>,
+    #[serde(default)]
+    lab_name_col_name: Option<String>,
+    #[serde(default)]
+    slide_id_col_idx: Option<u8>,
+    #[serde(default)]
+    slide_name_col_idx: Option<u8>,
+    #[serde(default)]
+    run_id_col_idx: Option<u8>,
+    #[serde(default)]
+    lab_name_col_idx: Option<u8>,
+}
+
+/// Where each field of a [`Slide`](super::slide::Slide) is in a row of the spreadsheet
+pub(super) struct ColumnIndices {
+    pub(super) slide_id: usize,
+    pub(super) slide_name: usize,
+    pub(super) run_id: usize,
+    pub(super) lab_name: usize,
 }
 
 impl SpreadsheetSpecification {
-    pub(super) fn to_url(&self) -> anyhow::Result<Url> {
-        let Self { id, range, .. } = self;
+    pub(super) fn has_header_row(&self) -> bool {
+        [
+            &self.slide_id_col_name,
+            &self.slide_name_col_name,
+            &self.run_id_col_name,
+            &self.lab_name_col_name,
+        ]
+        .iter()
+        .any(|n| n.is_some())
+    }
+
+    pub(super) fn column_indices(&self, header_row: Option<&[String]>) -> anyhow::Result<ColumnIndices> {
+        let Self {
+            slide_id_col_name,
+            slide_name_col_name,
+            run_id_col_name,
+            lab_name_col_name,
+            slide_id_col_idx,
+            slide_name_col_idx,
+            run_id_col_idx,
+            lab_name_col_idx,
+            ..
+        } = self;
+
+        Ok(ColumnIndices {
+            slide_id: column_index("slide ID", slide_id_col_name, *slide_id_col_idx, header_row)?,
+            slide_name: column_index("slide name", slide_name_col_name, *slide_name_col_idx, header_row)?,
+            run_id: column_index("run ID", run_id_col_name, *run_id_col_idx, header_row)?,
+            lab_name: column_index("lab name", lab_name_col_name, *lab_name_col_idx, header_row)?,
+        })
+    }
+}
 
-        let url = format!("https://sheets.googleapis.com/v4/spreadsheets/{id}/values/{range}");
+fn column_index(
+    field: &str,
+    name: &Option<String>,
+    idx: Option<u8>,
+    header_row: Option<&[String]>,
+) -> anyhow::Result<usize> {
+    let found = name
+        .as_ref()
+        .zip(header_row)
+        .and_then(|(name, header_row)| header_row.iter().position(|h| h.trim() == name));
 
-        Ok(Url::from_str(&url)?)
+    match (found, name, idx) {
+        (Some(found), ..) => Ok(found),
+        (None, Some(name), Some(idx)) => {
+            eprintln!("column '{name}' not found in the Xenium spreadsheet, so using column {idx} for {field}");
+            Ok(idx as usize)
+        }
+        (None, None, Some(idx)) => Ok(idx as usize),
+        (None, Some(name), None) => Err(anyhow!(
+            "column '{name}' (for {field}) not found in the header row of the Xenium spreadsheet"
+        )),
+        (None, None, None) => Err(anyhow!("no column specified for {field} in the Xenium spreadsheet")),
     }
 }
 
@@ -0,0 +1,44 @@
+use std::fs;
+
+use anyhow::Context;
+use camino::Utf8Path;
+
+use super::experiment::EXPERIMENT_FILENAME;
+
+// The `design` directory makes a delivery self-describing: it holds the slide's row from the spreadsheet along with
+// copies of the files from the instrument output that describe the experiment.
+const SLIDE_METADATA_FILENAME: &str = "slide.json";
+const INSTRUMENT_FILENAMES: [&str; 2] = [EXPERIMENT_FILENAME, "gene_panel.json"];
+
+/// Write the slide's metadata into `design_dir`, and copy the instrument's description of the experiment from
+/// `xeniumranger_dir`. For a slide with more than one region, each region's files go in their own directory.
+pub(super) fn populate(
+    design_dir: &Utf8Path,
+    region: Option<&str>,
+    xeniumranger_dir: &Utf8Path,
+    slide_metadata: &str,
+) -> anyhow::Result<()> {
+    let metadata_path = design_dir.join(SLIDE_METADATA_FILENAME);
+    fs::write(&metadata_path, slide_metadata).context(format!("failed to write {metadata_path}"))?;
+
+    let instrument_files_dir = region.map_or(design_dir.to_path_buf(), |r| design_dir.join(r));
+    fs::create_dir_all(&instrument_files_dir).context(format!("failed to create {instrument_files_dir}"))?;
+
+    for filename in INSTRUMENT_FILENAMES {
+        let (source, destination) = (xeniumranger_dir.join(filename), instrument_files_dir.join(filename));
+
+        if !source.exists() {
+            eprintln!("{source} does not exist, so it was not copied into {instrument_files_dir}");
+            continue;
+        }
+
+        // The copy keeps the original's permissions, so one left by an earlier delivery may be read-only
+        if destination.exists() {
+            fs::remove_file(&destination).context(format!("failed to remove {destination}"))?;
+        }
+
+        fs::copy(&source, &destination).context(format!("failed to copy {source} to {destination}"))?;
+    }
+
+    Ok(())
+}
@@ -1,10 +1,20 @@
-use std::collections::{HashMap, HashSet};
+use std::{collections::HashMap, sync::Arc};
 
-use anyhow::{Context, anyhow, ensure};
+use anyhow::{Context, anyhow, bail, ensure};
 use camino::{Utf8Path, Utf8PathBuf};
 use console::Term;
-
-use super::{config::StagingDirSpecification, slide::Slide};
+use itertools::Itertools;
+
+use super::{
+    config::StagingDirSpecification,
+    design,
+    experiment::{EXPERIMENT_FILENAME, Experiment, matches_dir_name_part},
+    plan::{Action, CollisionPolicy, PlannedMove},
+    progress::SlideProgress,
+    slide::Slide,
+    transfer,
+};
+use crate::problems::bail_on_problems;
 
 // The format for a xenium output file, somewhat stupidly, is:
 // ├── <DATE>__<SOME STRING>__<RUN ID>
@@ -13,22 +23,37 @@ use super::{config::StagingDirSpecification, slide::Slide};
 // This is dumb because we're using the SLIDE NAME instead of the SLIDE ID. The latter is made by us and guaranteed to
 // be unique.
 //
+// Directory names can be mistyped, so each output directory's `experiment.xenium` must agree with its name, and the
+// slide is identified by what `experiment.xenium` says.
+//
 // We want to rearrange this such that each of those subdirectories above is extracted and delivered as:
 // └── <SLIDE ID>-<RUN ID>_<SLIDE NAME>
 //     ├── design
 //     └── xeniumranger
+//
+// unless the slide has more than one region, in which case each region is delivered under `xeniumranger`:
+// └── <SLIDE ID>-<RUN ID>_<SLIDE NAME>
+//     ├── design
+//     │   ├── <REGION NAME>
+//     │   └── <ANOTHER REGION NAME>
+//     └── xeniumranger
+//         ├── <REGION NAME>
+//         └── <ANOTHER REGION NAME>
 
 #[derive(Debug)]
 pub(super) struct ParsedDataDir<'a> {
     path: &'a Utf8Path,
     run_id: &'a str,
     subdirs: Vec<SubDir>,
+    /// Output directories that were copied, but not completely removed before the move was interrupted
+    pub(super) interrupted_removals: Vec<Utf8PathBuf>,
 }
 
 #[derive(Debug)]
 struct SubDir {
     path: Utf8PathBuf,
-    slide_name: String, // This is so stupid
+    region_name: String,
+    experiment: Experiment,
 }
 
 impl<'a> ParsedDataDir<'a> {
@@ -48,6 +73,8 @@ impl<'a> ParsedDataDir<'a> {
             .context(format!("failed to read sub-directories of {dir}"))?;
         const MAX_SUBDIRS: usize = 8;
         let mut subdirs = Vec::with_capacity(MAX_SUBDIRS);
+        let mut mismatches = Vec::new();
+        let mut interrupted_removals = Vec::new();
         for subdir in raw_subdirs {
             let subdir = subdir?;
 
@@ -59,96 +86,225 @@ impl<'a> ParsedDataDir<'a> {
                 continue;
             }
 
-            let slide_name = path_name
-                .split("__")
-                .nth(1)
-                .ok_or(anyhow!("failed to get slide name for {path}"))?
-                .to_string();
+            // An output directory that was moved, but not completely removed before the move was interrupted
+            if transfer::is_removal_path(&path) {
+                interrupted_removals.push(path);
+                continue;
+            }
+
+            let [machine_id, slide_name, region_name, ..] = path_name.split("__").collect_vec()[..] else {
+                bail!(
+                    "failed to get slide name and region name for {path}, expected output-<MACHINE ID>__<SLIDE \
+                     NAME>__<REGION NAME>__..."
+                );
+            };
+            let machine_id = machine_id.strip_prefix("output-").unwrap_or(machine_id);
 
-            subdirs.push(SubDir { path, slide_name });
+            let experiment = Experiment::from_output_dir(&path)?;
+
+            let mut check = |field: &str, experiment_value: &str, dir_value: &str| {
+                if !matches_dir_name_part(experiment_value, dir_value) {
+                    mismatches.push(format!(
+                        "{path}: the {field} in the directory name is {dir_value}, but experiment.xenium says \
+                         {experiment_value}"
+                    ));
+                }
+            };
+            check("slide name", &experiment.slide_id, slide_name);
+            check("region name", &experiment.region_name, region_name);
+            check("run ID", &experiment.run_name, run_id);
+            if let Some(instrument_sn) = &experiment.instrument_sn {
+                check("instrument serial number", instrument_sn, machine_id);
+            }
+
+            subdirs.push(SubDir {
+                region_name: region_name.to_string(),
+                path,
+                experiment,
+            });
         }
+        subdirs.sort_by(|a, b| a.path.cmp(&b.path));
+
+        bail_on_problems(
+            format!("the names of the Xenium output directories in {dir}"),
+            &mismatches,
+        )?;
 
         Ok(Self {
             path: dir,
             run_id,
             subdirs,
+            interrupted_removals,
         })
     }
 
     // Should this function be split into one that finds the matching slides and one that renames?
     pub(super) fn construct_new_subdir_names<'b>(
         &'a self,
-        slides: &'b HashMap<&str, Vec<Slide>>,
+        slides: &'b HashMap<&'b str, Vec<Slide<'b>>>,
         staging_dir_spec: &StagingDirSpecification,
-    ) -> anyhow::Result<Vec<(&'b Utf8Path, Utf8PathBuf)>>
+        collision_policy: CollisionPolicy,
+    ) -> anyhow::Result<Vec<PlannedMove<'b>>>
     where
         'a: 'b,
     {
-        let Self { path, run_id, subdirs } = self;
+        let Self {
+            path, run_id, subdirs, ..
+        } = self;
         let matching_slides = slides.get(run_id).ok_or(anyhow!(
             "failed to find Xenium slides from spreadsheet from path {path}"
         ))?;
 
-        ensure!(
-            matching_slides.len() == subdirs.len(),
-            "spreadsheet indicates {} slides for this run, but the data directory {path} has {} subdirectories (each \
-             corresponding to a slide)",
-            matching_slides.len(),
-            subdirs.len()
-        );
+        let mut slides_by_name = HashMap::new();
+        for slide in matching_slides {
+            let is_unseen = slides_by_name.insert(slide.name, slide).is_none();
+            ensure!(
+                is_unseen,
+                "found multiple slides with the name {} in the spreadsheet",
+                slide.name
+            );
+        }
 
-        let mut subdirs_paired_with_slides = Vec::new();
-        let mut seen_spreadsheet_slide_names = HashSet::new();
-        for SubDir {
-            path,
-            slide_name: subdir_slide_name,
-        } in subdirs
-        {
-            for Slide {
-                id: spreadsheet_slide_id,
-                name: spreadsheet_slide_name,
-                run_id,
-                lab_name,
-            } in matching_slides
-            {
-                if subdir_slide_name != spreadsheet_slide_name {
-                    continue;
+        // A slide with more than one region has one output directory per region, so rather than expecting one
+        // subdirectory per slide, every slide in the spreadsheet must have at least one region and every region must
+        // belong to a slide in the spreadsheet
+        let regions_by_slide_name = subdirs.iter().into_group_map_by(|s| s.experiment.slide_id.as_str());
+
+        let mut problems = Vec::new();
+        for (slide_name, regions) in &regions_by_slide_name {
+            if !slides_by_name.contains_key(slide_name) {
+                let paths = regions.iter().map(|r| &r.path).join(", ");
+                problems.push(format!(
+                    "{paths} hold slide {slide_name} according to experiment.xenium, but the spreadsheet doesn't list \
+                     that slide for run {run_id}"
+                ));
+            }
+
+            for (region_name, n_dirs) in regions.iter().counts_by(|r| &r.region_name) {
+                if n_dirs > 1 {
+                    problems.push(format!(
+                        "found {n_dirs} output directories for region {region_name} of slide {slide_name}"
+                    ));
                 }
+            }
+        }
+        for slide_name in slides_by_name.keys().sorted() {
+            if regions_by_slide_name.contains_key(slide_name) {
+                continue;
+            }
 
-                let is_unseen = seen_spreadsheet_slide_names.insert(*spreadsheet_slide_name);
-                ensure!(
-                    is_unseen,
-                    "found multiple slides with the name {spreadsheet_slide_name} in the spreadsheet"
+            // A slide's output directory is gone once it has been moved, so running the same command again after an
+            // interrupted run only finds the slides that are left
+            let xeniumranger_dir = xeniumranger_dir(slides_by_name[slide_name], staging_dir_spec)?;
+            if xeniumranger_dir.exists() || transfer::journal_path(&xeniumranger_dir).exists() {
+                eprintln!(
+                    "{path} has no output directory for slide {slide_name}, as it was already moved to \
+                     {xeniumranger_dir}"
                 );
+                continue;
+            }
 
-                let new_path_name = format!("{spreadsheet_slide_id}-{run_id}_{spreadsheet_slide_name}");
-
-                let lab_staging_dir = staging_dir_spec.lab_staging_dir(*lab_name)?;
-                let lab_staging_dir = lab_staging_dir
-                    .canonicalize_utf8()
-                    .context(format!("failed to get absolute path for {lab_staging_dir}"))?;
+            problems.push(format!(
+                "the spreadsheet lists slide {slide_name} for run {run_id}, but {path} has no output directory for \
+                 it, and it hasn't been moved to {xeniumranger_dir}"
+            ));
+        }
 
-                let new_path = lab_staging_dir.join(new_path_name).join("xeniumranger");
+        bail_on_problems(format!("the slides in {path}"), &problems)?;
 
-                subdirs_paired_with_slides.push((path.as_path(), new_path));
+        let mut planned_moves = Vec::new();
+        for (slide_name, regions) in regions_by_slide_name.into_iter().sorted_by_key(|(n, _)| *n) {
+            let slide @ Slide {
+                id: slide_id,
+                run_id,
+                lab_name,
+                ..
+            } = slides_by_name[slide_name];
+
+            let new_path_name = format!("{slide_id}-{run_id}_{slide_name}");
+
+            let lab_staging_dir = staging_dir_spec.lab_staging_dir(lab_name)?;
+            let lab_staging_dir = lab_staging_dir
+                .canonicalize_utf8()
+                .context(format!("failed to get absolute path for {lab_staging_dir}"))?;
+
+            // A slide with one region is delivered as it always has been, without a directory for the region. If an
+            // interrupted run already moved some regions of a slide, only the rest are left
+            let has_multiple_regions =
+                regions.len() > 1 || is_delivered_by_region(&xeniumranger_dir(slide, staging_dir_spec)?)?;
+            for SubDir { path, region_name, .. } in regions {
+                planned_moves.push(PlannedMove::new(
+                    path,
+                    *slide,
+                    lab_staging_dir.clone(),
+                    &new_path_name,
+                    has_multiple_regions.then_some(region_name.as_str()),
+                    collision_policy,
+                ));
             }
         }
 
-        Ok(subdirs_paired_with_slides)
+        Ok(planned_moves)
     }
 }
 
-pub(super) fn confirm_move(term: &Term, old_path: &Utf8Path, new_path: &Utf8Path) -> anyhow::Result<bool> {
-    let err = "failed to write line to terminal";
-    if new_path.exists() {
-        term.write_line(&format!(
-            "skipping renaming {old_path} to {new_path}, as it already exists"
-        ))
-        .context(err)?;
+/// The `xeniumranger` directory that `slide` is delivered to
+fn xeniumranger_dir(slide: &Slide, staging_dir_spec: &StagingDirSpecification) -> anyhow::Result<Utf8PathBuf> {
+    let Slide {
+        id,
+        name,
+        run_id,
+        lab_name,
+    } = slide;
+
+    Ok(staging_dir_spec
+        .lab_staging_dir(lab_name)?
+        .join(format!("{id}-{run_id}_{name}"))
+        .join("xeniumranger"))
+}
+
+/// Whether `xeniumranger_dir` holds a directory for each region of its slide (or the journal of moving one), rather
+/// than the output of a slide with one region
+fn is_delivered_by_region(xeniumranger_dir: &Utf8Path) -> anyhow::Result<bool> {
+    if !xeniumranger_dir.is_dir() {
+        return Ok(false);
     }
 
-    term.write_line(&format!("move {old_path} -> {new_path} (y/n)?"))
-        .context(err)?;
+    for entry in xeniumranger_dir
+        .read_dir_utf8()
+        .context(format!("failed to read {xeniumranger_dir}"))?
+    {
+        let entry = entry?;
+        let name = entry.file_name();
+
+        if entry.path().join(EXPERIMENT_FILENAME).is_file() || (name.starts_with('.') && name.ends_with(".journal")) {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+pub(super) fn confirm_move(term: &Term, planned_move: &PlannedMove) -> anyhow::Result<bool> {
+    let err = "failed to write line to terminal";
+
+    let PlannedMove {
+        source,
+        destination,
+        action,
+        backup,
+        ..
+    } = planned_move;
+    let prompt = match (action, backup) {
+        (Action::Resume, _) => format!("resume moving {source} -> {destination} (y/n)?"),
+        (Action::Merge, _) => format!("merge {source} into existing {destination} (y/n)?"),
+        (Action::Overwrite, Some(backup)) => {
+            format!("move {source} -> {destination}, backing up existing {destination} to {backup} (y/n)?")
+        }
+        _ => format!("move {source} -> {destination} (y/n)?"),
+    };
+
+    term.write_line(&prompt).context(err)?;
     let res = loop {
         let input = term.read_char().context("failed to read char from terminal")?;
         if input == 'y' {
@@ -163,32 +319,175 @@ pub(super) fn confirm_move(term: &Term, old_path: &Utf8Path, new_path: &Utf8Path
     Ok(res)
 }
 
-pub(super) async fn rename(old_path: &Utf8Path, new_path: &Utf8Path) -> anyhow::Result<()> {
-    let old_path = old_path
-        .canonicalize_utf8()
-        .context(format!("failed to get absolute path for {old_path}"))?;
+/// Move a slide (or one region of it) into the staging directory, returning the checksums of any files that had to be
+/// copied
+pub(super) async fn rename(
+    planned_move: &PlannedMove<'_>,
+    progress: Arc<SlideProgress>,
+) -> anyhow::Result<HashMap<Utf8PathBuf, String>> {
+    let PlannedMove {
+        source,
+        slide,
+        region,
+        design_dir,
+        destination,
+        backup,
+        ..
+    } = planned_move;
+
+    let slide_metadata = serde_json::to_string_pretty(slide)?;
+
+    if let Some(backup) = backup {
+        if let Some(backups_dir) = backup.parent() {
+            tokio::fs::create_dir_all(backups_dir)
+                .await
+                .context(format!("failed to create directory {backups_dir}"))?;
+        }
+        tokio::fs::rename(destination, backup)
+            .await
+            .context(format!("failed to back up {destination} to {backup}"))?;
+    }
 
-    if new_path.exists() {
-        println!("skipping renaming {old_path} to {new_path}, as it already exists");
+    // The parent is the slide directory, `xeniumranger` if the slide has more than one region, or the directory holding
+    // each version of a xeniumranger rerun
+    for dir in design_dir.iter().map(|d| d.as_path()).chain(destination.parent()) {
+        tokio::fs::create_dir_all(dir)
+            .await
+            .context(format!("failed to create directory {dir}"))?;
     }
 
-    tokio::fs::create_dir_all(new_path.join("design"))
-        .await
-        .context("failed to create directory {new_path}")?;
+    let (source, region, design_dir, destination) = (
+        source.to_path_buf(),
+        region.clone(),
+        design_dir.clone(),
+        destination.clone(),
+    );
+    tokio::task::spawn_blocking(move || {
+        progress.start(transfer::dir_size(&source)?);
+        let checksums = transfer::move_dir(&source, &destination, &progress.bytes_moved)?;
+        if let Some(design_dir) = design_dir {
+            design::populate(&design_dir, region.as_deref(), &destination, &slide_metadata)?;
+        }
 
-    let mut mv_cmd = tokio::process::Command::new("mv");
-    mv_cmd.arg(&old_path).arg(&new_path);
+        Ok(checksums)
+    })
+    .await?
+}
 
-    let output = mv_cmd
-        .output()
-        .await
-        .context(format!("failed to move {old_path} to {new_path}"))?;
+#[cfg(test)]
+mod tests {
+    use std::fs;
 
-    ensure!(
-        output.status.success(),
-        "failed to move {old_path} to {new_path}:\n{}",
-        String::from_utf8(output.stderr).unwrap()
-    );
+    use pretty_assertions::assert_eq;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    const SLIDE: Slide = Slide {
+        id: "XA",
+        name: "slideA",
+        run_id: "RUN1",
+        lab_name: "Lab A",
+    };
+
+    /// A lab staging directory holding a slide whose rerun `v1_rerun` has already been delivered, and a new output to
+    /// deliver in its place
+    struct Fixture {
+        _dir: TempDir,
+        lab_staging_dir: Utf8PathBuf,
+        slide_dir: Utf8PathBuf,
+        destination: Utf8PathBuf,
+        source: Utf8PathBuf,
+    }
 
-    Ok(())
+    impl Fixture {
+        fn new() -> Self {
+            let dir = TempDir::new().unwrap();
+            let root = Utf8PathBuf::from_path_buf(dir.path().to_path_buf()).unwrap();
+
+            let lab_staging_dir = root.join("lab_a");
+            let slide_dir = lab_staging_dir.join("XA-RUN1_slideA");
+            let destination = slide_dir.join("xeniumranger-resegment/v1_rerun");
+            fs::create_dir_all(destination.join("outs")).unwrap();
+            fs::write(destination.join("outs/cells.csv"), "old cells").unwrap();
+            fs::write(destination.join("outs/old_only.txt"), "old").unwrap();
+
+            let source = root.join("rerun");
+            fs::create_dir_all(source.join("outs")).unwrap();
+            fs::write(source.join("outs/cells.csv"), "new cells").unwrap();
+            fs::write(source.join("outs/new_only.txt"), "new").unwrap();
+
+            Self {
+                _dir: dir,
+                lab_staging_dir,
+                slide_dir,
+                destination,
+                source,
+            }
+        }
+
+        fn plan(&self, collision_policy: CollisionPolicy) -> PlannedMove<'_> {
+            PlannedMove::rerun(
+                &self.source,
+                SLIDE,
+                self.lab_staging_dir.clone(),
+                self.slide_dir.clone(),
+                self.destination.clone(),
+                collision_policy,
+            )
+        }
+
+        fn read(&self, relative_path: &str) -> String {
+            fs::read_to_string(self.destination.join(relative_path)).unwrap()
+        }
+    }
+
+    fn progress() -> Arc<SlideProgress> {
+        Arc::new(SlideProgress::new(String::new()))
+    }
+
+    #[tokio::test]
+    async fn backs_up_overwritten_destination_outside_slide_dir() {
+        let fixture = Fixture::new();
+        let planned_move = fixture.plan(CollisionPolicy::OverwriteWithBackup);
+        let backup = planned_move.backup.clone().unwrap();
+
+        rename(&planned_move, progress()).await.unwrap();
+
+        assert_eq!(fixture.read("outs/cells.csv"), "new cells");
+        assert!(!fixture.destination.join("outs/old_only.txt").exists());
+        assert!(!fixture.source.exists());
+
+        assert!(
+            backup.starts_with(
+                fixture
+                    .lab_staging_dir
+                    .join(".backups/XA-RUN1_slideA/xeniumranger-resegment")
+            )
+        );
+        assert_eq!(fs::read_to_string(backup.join("outs/cells.csv")).unwrap(), "old cells");
+        assert_eq!(fs::read_to_string(backup.join("outs/old_only.txt")).unwrap(), "old");
+    }
+
+    #[tokio::test]
+    async fn merges_into_existing_destination() {
+        let fixture = Fixture::new();
+        let planned_move = fixture.plan(CollisionPolicy::Merge);
+        assert_eq!(planned_move.action, Action::Merge);
+
+        let checksums = rename(&planned_move, progress()).await.unwrap();
+
+        assert_eq!(fixture.read("outs/cells.csv"), "new cells");
+        assert_eq!(fixture.read("outs/old_only.txt"), "old");
+        assert_eq!(fixture.read("outs/new_only.txt"), "new");
+        assert_eq!(
+            checksums.keys().sorted().collect_vec(),
+            [
+                &fixture.destination.join("outs/cells.csv"),
+                &fixture.destination.join("outs/new_only.txt")
+            ]
+        );
+        assert!(!fixture.source.exists());
+        assert!(!fixture.lab_staging_dir.join(".backups").exists());
+    }
 }
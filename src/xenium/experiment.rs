@@ -0,0 +1,40 @@
+use std::fs;
+
+use anyhow::Context;
+use camino::Utf8Path;
+use serde::Deserialize;
+
+pub(super) const EXPERIMENT_FILENAME: &str = "experiment.xenium";
+
+/// The parts of `experiment.xenium`, which the instrument writes into each output directory, that identify the slide.
+/// What the instrument calls the slide ID is what we call the slide name.
+#[derive(Deserialize, Debug)]
+pub(super) struct Experiment {
+    pub(super) slide_id: String,
+    pub(super) region_name: String,
+    pub(super) run_name: String,
+    #[serde(default)]
+    pub(super) instrument_sn: Option<String>,
+}
+
+impl Experiment {
+    pub(super) fn from_output_dir(dir: &Utf8Path) -> anyhow::Result<Self> {
+        let path = dir.join(EXPERIMENT_FILENAME);
+        let contents = fs::read_to_string(&path).context(format!("failed to read {path}"))?;
+
+        serde_json::from_str(&contents).context(format!("failed to parse {path}"))
+    }
+}
+
+/// Whether a value from `experiment.xenium` matches the same value in a directory name. The instrument replaces
+/// characters that don't belong in a path when it names directories, so only letters, digits, `-`, and `_` are
+/// compared as they are.
+pub(super) fn matches_dir_name_part(value: &str, dir_name_part: &str) -> bool {
+    let sanitize = |s: &str| -> String {
+        s.chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+            .collect()
+    };
+
+    sanitize(value) == sanitize(dir_name_part)
+}
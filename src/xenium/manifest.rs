@@ -0,0 +1,222 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+};
+
+use anyhow::{Context, anyhow};
+use camino::{Utf8Path, Utf8PathBuf};
+use itertools::Itertools;
+
+use super::transfer::sha256_file;
+use crate::problems::bail_on_problems;
+
+// Every staged slide directory gets a manifest of the SHA-256 checksum of each file in it, with paths relative to the
+// slide directory. It's in the same format as `sha256sum`, so end users can check their copy with
+// `sha256sum --check manifest.sha256` from inside the slide directory, without needing `scbl-utils`.
+pub(super) const MANIFEST_FILENAME: &str = "manifest.sha256";
+
+/// Write the manifest for `slide_dir`. Files whose checksums are already in `known_checksums` (keyed by their full
+/// path) aren't read again
+pub(super) fn write(slide_dir: &Utf8Path, known_checksums: &HashMap<Utf8PathBuf, String>) -> anyhow::Result<()> {
+    let mut lines = Vec::new();
+    for relative_path in list_files(slide_dir)? {
+        let path = slide_dir.join(&relative_path);
+
+        let checksum = match known_checksums.get(&path) {
+            Some(checksum) => checksum.clone(),
+            None => sha256_file(&path)?.1,
+        };
+
+        lines.push(format!("{checksum}  {relative_path}\n"));
+    }
+
+    let manifest_path = slide_dir.join(MANIFEST_FILENAME);
+    fs::write(&manifest_path, lines.concat()).context(format!("failed to write {manifest_path}"))
+}
+
+/// Read the checksums in `slide_dir`'s manifest, keyed by their paths relative to `slide_dir`
+pub(super) fn read(slide_dir: &Utf8Path) -> anyhow::Result<BTreeMap<Utf8PathBuf, String>> {
+    let manifest_path = slide_dir.join(MANIFEST_FILENAME);
+    let contents = fs::read_to_string(&manifest_path).context(format!("failed to read {manifest_path}"))?;
+
+    contents
+        .lines()
+        .map(|line| {
+            line.split_once("  ")
+                .filter(|(checksum, _)| checksum.len() == 64 && checksum.chars().all(|c| c.is_ascii_hexdigit()))
+                .map(|(checksum, path)| (Utf8PathBuf::from(path), checksum.to_ascii_lowercase()))
+                .ok_or(anyhow!("malformed line in {manifest_path}: {line}"))
+        })
+        .try_collect()
+}
+
+/// Check every file in `slide_dir` against its manifest, failing on any changed, missing, or unexpected file
+pub(super) fn verify(slide_dir: &Utf8Path) -> anyhow::Result<usize> {
+    let expected_checksums = read(slide_dir)?;
+
+    let mut problems = Vec::new();
+    for (relative_path, expected_checksum) in &expected_checksums {
+        let path = slide_dir.join(relative_path);
+
+        if !path.exists() {
+            problems.push(format!("{path} is missing"));
+            continue;
+        }
+
+        match sha256_file(&path) {
+            Ok((_, checksum)) if checksum == *expected_checksum => {}
+            Ok((_, checksum)) => problems.push(format!(
+                "{path} has checksum {checksum}, but the manifest lists {expected_checksum}"
+            )),
+            Err(e) => problems.push(format!("{e:#}")),
+        }
+    }
+
+    for relative_path in list_files(slide_dir)? {
+        if !expected_checksums.contains_key(&relative_path) {
+            problems.push(format!("{} is not in the manifest", slide_dir.join(relative_path)));
+        }
+    }
+
+    bail_on_problems(slide_dir, &problems)?;
+
+    Ok(expected_checksums.len())
+}
+
+/// Every regular file under `slide_dir` other than the manifest itself, relative to `slide_dir` and sorted. Symbolic
+/// links are left out
+fn list_files(slide_dir: &Utf8Path) -> anyhow::Result<Vec<Utf8PathBuf>> {
+    let mut paths = Vec::new();
+    collect_files(slide_dir, Utf8Path::new(""), &mut paths)?;
+    paths.retain(|p| p != MANIFEST_FILENAME);
+    paths.sort();
+
+    Ok(paths)
+}
+
+fn collect_files(root: &Utf8Path, relative_dir: &Utf8Path, paths: &mut Vec<Utf8PathBuf>) -> anyhow::Result<()> {
+    let dir = root.join(relative_dir);
+
+    for entry in dir.read_dir_utf8().context(format!("failed to read {dir}"))? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let relative_path = relative_dir.join(entry.file_name());
+
+        if file_type.is_dir() {
+            collect_files(root, &relative_path, paths)?;
+        } else if file_type.is_file() {
+            paths.push(relative_path);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use sha2::{Digest, Sha256};
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn sha256(contents: &str) -> String {
+        Sha256::digest(contents).iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    /// A slide directory with a nested file and a file whose name has spaces, with its manifest written
+    fn staged_slide_dir() -> (TempDir, Utf8PathBuf) {
+        let dir = TempDir::new().unwrap();
+        let slide_dir = Utf8PathBuf::from_path_buf(dir.path().to_path_buf()).unwrap();
+
+        fs::create_dir_all(slide_dir.join("xeniumranger/cell feature matrix")).unwrap();
+        fs::write(slide_dir.join("xeniumranger/experiment.xenium"), "experiment").unwrap();
+        fs::write(
+            slide_dir.join("xeniumranger/cell feature matrix/barcodes 1.tsv"),
+            "barcodes",
+        )
+        .unwrap();
+        write(&slide_dir, &HashMap::new()).unwrap();
+
+        (dir, slide_dir)
+    }
+
+    #[test]
+    fn writes_sha256sum_format() {
+        let (_dir, slide_dir) = staged_slide_dir();
+
+        assert_eq!(
+            fs::read_to_string(slide_dir.join(MANIFEST_FILENAME)).unwrap(),
+            format!(
+                "{}  xeniumranger/cell feature matrix/barcodes 1.tsv\n{}  xeniumranger/experiment.xenium\n",
+                sha256("barcodes"),
+                sha256("experiment")
+            )
+        );
+    }
+
+    #[test]
+    fn reads_what_was_written() {
+        let (_dir, slide_dir) = staged_slide_dir();
+
+        assert_eq!(
+            read(&slide_dir).unwrap(),
+            BTreeMap::from([
+                (
+                    Utf8PathBuf::from("xeniumranger/cell feature matrix/barcodes 1.tsv"),
+                    sha256("barcodes")
+                ),
+                (
+                    Utf8PathBuf::from("xeniumranger/experiment.xenium"),
+                    sha256("experiment")
+                ),
+            ])
+        );
+        assert_eq!(verify(&slide_dir).unwrap(), 2);
+    }
+
+    #[test]
+    fn reuses_known_checksums() {
+        let (_dir, slide_dir) = staged_slide_dir();
+        let path = slide_dir.join("xeniumranger/experiment.xenium");
+
+        write(&slide_dir, &HashMap::from([(path, "0".repeat(64))])).unwrap();
+
+        assert_eq!(
+            read(&slide_dir).unwrap()[Utf8Path::new("xeniumranger/experiment.xenium")],
+            "0".repeat(64)
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        let (_dir, slide_dir) = staged_slide_dir();
+        fs::write(
+            slide_dir.join(MANIFEST_FILENAME),
+            "not-a-checksum  xeniumranger/experiment.xenium\n",
+        )
+        .unwrap();
+
+        assert!(read(&slide_dir).is_err());
+    }
+
+    #[test]
+    fn detects_modified_missing_and_unexpected_files() {
+        let (_dir, slide_dir) = staged_slide_dir();
+        fs::write(slide_dir.join("xeniumranger/experiment.xenium"), "modified").unwrap();
+        fs::remove_file(slide_dir.join("xeniumranger/cell feature matrix/barcodes 1.tsv")).unwrap();
+        fs::write(slide_dir.join("xeniumranger/extra.txt"), "extra").unwrap();
+
+        let err = verify(&slide_dir).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "found 3 problems with {slide_dir}:\n  - {slide_dir}/xeniumranger/cell feature matrix/barcodes 1.tsv \
+                 is missing\n  - {slide_dir}/xeniumranger/experiment.xenium has checksum {}, but the manifest lists \
+                 {}\n  - {slide_dir}/xeniumranger/extra.txt is not in the manifest",
+                sha256("modified"),
+                sha256("experiment")
+            )
+        );
+    }
+}
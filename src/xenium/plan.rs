@@ -0,0 +1,379 @@
+use std::fs;
+
+use anyhow::Context;
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::Utc;
+use itertools::Itertools;
+use serde::Serialize;
+
+use super::{slide::Slide, transfer};
+use crate::problems::bail_on_problems;
+
+/// Backups of overwritten destinations are kept in this hidden directory of the lab staging directory, outside the
+/// slide directories that are delivered
+const BACKUPS_DIRNAME: &str = ".backups";
+
+/// Everything `stage-xenium` is going to do, worked out before anything is touched so that it can be reviewed first
+#[derive(Serialize)]
+pub(super) struct Plan<'a> {
+    pub(super) moves: Vec<PlannedMove<'a>>,
+}
+
+#[derive(Serialize)]
+pub(super) struct PlannedMove<'a> {
+    pub(super) source: &'a Utf8Path,
+    pub(super) slide: Slide<'a>,
+    /// Only set if the slide has more than one region
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) region: Option<String>,
+    pub(super) lab_staging_dir: Utf8PathBuf,
+    /// `<SLIDE ID>-<RUN ID>_<SLIDE NAME>`, which holds the `design` and `xeniumranger` directories
+    pub(super) slide_dir: Utf8PathBuf,
+    /// Not set for a xeniumranger rerun, which is delivered next to a slide whose `design` directory already exists
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) design_dir: Option<Utf8PathBuf>,
+    /// The `xeniumranger` directory (or region directory inside it) that `source` becomes, or the versioned directory
+    /// for a xeniumranger rerun
+    pub(super) destination: Utf8PathBuf,
+    pub(super) action: Action,
+    /// Where the existing destination is moved to before it's overwritten
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) backup: Option<Utf8PathBuf>,
+}
+
+/// What to do when a slide's `xeniumranger` directory already exists in the staging directory
+#[derive(Debug, Clone, Copy, Default, clap::ValueEnum)]
+pub enum CollisionPolicy {
+    /// Leave the source where it is
+    #[default]
+    Skip,
+    /// Refuse to move anything
+    Fail,
+    /// Move the source's files into the existing directory, replacing any with the same name
+    Merge,
+    /// Move the existing directory to `<NAME>.backup-<TIMESTAMP>` in the lab staging directory's `.backups`, then move
+    /// the source
+    OverwriteWithBackup,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum Action {
+    Move,
+    /// An earlier move to the destination was interrupted, so it's picked up where it stopped
+    Resume,
+    /// The destination already exists, so the source is left where it is
+    Skip,
+    /// The destination already exists, so nothing is moved at all
+    Fail,
+    Merge,
+    Overwrite,
+}
+
+impl<'a> PlannedMove<'a> {
+    pub(super) fn new(
+        source: &'a Utf8Path,
+        slide: Slide<'a>,
+        lab_staging_dir: Utf8PathBuf,
+        slide_dir_name: &str,
+        region: Option<&str>,
+        collision_policy: CollisionPolicy,
+    ) -> Self {
+        let slide_dir = lab_staging_dir.join(slide_dir_name);
+        let design_dir = slide_dir.join("design");
+        let xeniumranger_dir = slide_dir.join("xeniumranger");
+        let destination = region.map_or(xeniumranger_dir.clone(), |r| xeniumranger_dir.join(r));
+        let (action, backup) = plan_action(&lab_staging_dir, &destination, collision_policy);
+
+        Self {
+            source,
+            slide,
+            region: region.map(str::to_string),
+            lab_staging_dir,
+            slide_dir,
+            design_dir: Some(design_dir),
+            destination,
+            action,
+            backup,
+        }
+    }
+
+    /// Plan the move of a xeniumranger rerun into `destination`, a versioned directory inside an already staged
+    /// `slide_dir`
+    pub(super) fn rerun(
+        source: &'a Utf8Path,
+        slide: Slide<'a>,
+        lab_staging_dir: Utf8PathBuf,
+        slide_dir: Utf8PathBuf,
+        destination: Utf8PathBuf,
+        collision_policy: CollisionPolicy,
+    ) -> Self {
+        let (action, backup) = plan_action(&lab_staging_dir, &destination, collision_policy);
+
+        Self {
+            source,
+            slide,
+            region: None,
+            lab_staging_dir,
+            slide_dir,
+            design_dir: None,
+            destination,
+            action,
+            backup,
+        }
+    }
+}
+
+fn plan_action(
+    lab_staging_dir: &Utf8Path,
+    destination: &Utf8Path,
+    collision_policy: CollisionPolicy,
+) -> (Action, Option<Utf8PathBuf>) {
+    // An interrupted move is our own doing rather than a collision, so it's always resumed
+    let action = if !destination.exists() {
+        Action::Move
+    } else if transfer::journal_path(destination).exists() {
+        Action::Resume
+    } else {
+        match collision_policy {
+            CollisionPolicy::Skip => Action::Skip,
+            CollisionPolicy::Fail => Action::Fail,
+            CollisionPolicy::Merge => Action::Merge,
+            CollisionPolicy::OverwriteWithBackup => Action::Overwrite,
+        }
+    };
+
+    // Anything left in the slide directory would be checksummed and delivered along with it, so the backup mirrors the
+    // destination's path under the backups directory instead
+    let backup = (action == Action::Overwrite).then(|| {
+        let relative_destination = destination.strip_prefix(lab_staging_dir).unwrap_or(destination);
+        let name = destination.file_name().unwrap_or_default();
+        let timestamp = Utc::now().format("%Y%m%dT%H%M%SZ");
+
+        lab_staging_dir
+            .join(BACKUPS_DIRNAME)
+            .join(relative_destination)
+            .with_file_name(format!("{name}.backup-{timestamp}"))
+    });
+
+    (action, backup)
+}
+
+impl Plan<'_> {
+    pub(super) fn to_move(&self) -> impl Iterator<Item = &PlannedMove<'_>> {
+        self.moves
+            .iter()
+            .filter(|m| !matches!(m.action, Action::Skip | Action::Fail))
+    }
+
+    /// Under [`CollisionPolicy::Fail`], any collision stops the whole plan from being carried out
+    pub(super) fn ensure_no_failures(&self) -> anyhow::Result<()> {
+        let collisions = self
+            .moves
+            .iter()
+            .filter(|m| m.action == Action::Fail)
+            .map(|m| format!("{} already exists", m.destination))
+            .collect_vec();
+
+        bail_on_problems("the destinations", &collisions).context("refusing to move anything")
+    }
+
+    pub(super) fn print(&self) {
+        for PlannedMove {
+            source,
+            lab_staging_dir,
+            design_dir,
+            destination,
+            action,
+            backup,
+            ..
+        } in &self.moves
+        {
+            let action = match action {
+                Action::Move => "move",
+                Action::Resume => "resume (an earlier move was interrupted)",
+                Action::Skip => "skip (destination already exists)",
+                Action::Fail => "fail (destination already exists)",
+                Action::Merge => "merge into existing destination",
+                Action::Overwrite => "overwrite existing destination",
+            };
+
+            println!("{source}");
+            println!("  action:                {action}");
+            println!("  lab staging directory: {lab_staging_dir}");
+            println!("  destination:           {destination}");
+            if let Some(backup) = backup {
+                println!("  backup:                {backup}");
+            }
+            if let Some(design_dir) = design_dir {
+                println!("  design directory:      {design_dir}");
+            }
+        }
+
+        let n_moves = self.to_move().count();
+        println!(
+            "{n_moves} directories to move, {} not moved because their destinations already exist",
+            self.moves.len() - n_moves
+        );
+    }
+
+    pub(super) fn write_json(&self, path: &Utf8Path) -> anyhow::Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?).context(format!("failed to write plan to {path}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    const SLIDE: Slide = Slide {
+        id: "XA",
+        name: "slideA",
+        run_id: "RUN1",
+        lab_name: "Lab A",
+    };
+
+    /// A lab staging directory holding a slide that has already been delivered, returning the slide's `xeniumranger`
+    /// directory
+    fn delivered_slide(lab_staging_dir: &Utf8Path) -> Utf8PathBuf {
+        let destination = lab_staging_dir.join("XA-RUN1_slideA/xeniumranger");
+        fs::create_dir_all(&destination).unwrap();
+        fs::write(destination.join("experiment.xenium"), "delivered").unwrap();
+
+        destination
+    }
+
+    fn utf8_tempdir() -> (TempDir, Utf8PathBuf) {
+        let dir = TempDir::new().unwrap();
+        let path = Utf8PathBuf::from_path_buf(dir.path().to_path_buf()).unwrap();
+
+        (dir, path)
+    }
+
+    #[test]
+    fn moves_to_new_destinations() {
+        let (_dir, lab_staging_dir) = utf8_tempdir();
+        let destination = lab_staging_dir.join("XA-RUN1_slideA/xeniumranger");
+
+        for policy in [
+            CollisionPolicy::Skip,
+            CollisionPolicy::Fail,
+            CollisionPolicy::Merge,
+            CollisionPolicy::OverwriteWithBackup,
+        ] {
+            assert_eq!(
+                plan_action(&lab_staging_dir, &destination, policy),
+                (Action::Move, None)
+            );
+        }
+    }
+
+    #[test]
+    fn applies_collision_policy_to_existing_destinations() {
+        let (_dir, lab_staging_dir) = utf8_tempdir();
+        let destination = delivered_slide(&lab_staging_dir);
+
+        for (policy, expected_action) in [
+            (CollisionPolicy::Skip, Action::Skip),
+            (CollisionPolicy::Fail, Action::Fail),
+            (CollisionPolicy::Merge, Action::Merge),
+        ] {
+            assert_eq!(
+                plan_action(&lab_staging_dir, &destination, policy),
+                (expected_action, None)
+            );
+        }
+
+        let (action, backup) = plan_action(&lab_staging_dir, &destination, CollisionPolicy::OverwriteWithBackup);
+        assert_eq!(action, Action::Overwrite);
+
+        let backup = backup.unwrap();
+        let backup_name = backup.file_name().unwrap();
+        assert_eq!(
+            backup.parent(),
+            Some(lab_staging_dir.join(".backups/XA-RUN1_slideA").as_path())
+        );
+        assert!(backup_name.starts_with("xeniumranger.backup-"), "{backup_name}");
+        assert!(!backup.starts_with(lab_staging_dir.join("XA-RUN1_slideA")));
+    }
+
+    #[test]
+    fn resumes_interrupted_moves_whatever_the_policy() {
+        let (_dir, lab_staging_dir) = utf8_tempdir();
+        let destination = delivered_slide(&lab_staging_dir);
+        fs::write(transfer::journal_path(&destination), "").unwrap();
+
+        for policy in [
+            CollisionPolicy::Skip,
+            CollisionPolicy::Fail,
+            CollisionPolicy::Merge,
+            CollisionPolicy::OverwriteWithBackup,
+        ] {
+            assert_eq!(
+                plan_action(&lab_staging_dir, &destination, policy),
+                (Action::Resume, None)
+            );
+        }
+    }
+
+    #[test]
+    fn skips_without_touching_source() {
+        let (_dir, root) = utf8_tempdir();
+        let lab_staging_dir = root.join("lab_a");
+        delivered_slide(&lab_staging_dir);
+
+        let source = root.join("output-M1__slideA__reg1");
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join("experiment.xenium"), "new").unwrap();
+
+        let planned_move = PlannedMove::new(
+            &source,
+            SLIDE,
+            lab_staging_dir.clone(),
+            "XA-RUN1_slideA",
+            None,
+            CollisionPolicy::Skip,
+        );
+        assert_eq!(planned_move.action, Action::Skip);
+
+        let plan = Plan {
+            moves: vec![planned_move],
+        };
+        assert_eq!(plan.to_move().count(), 0);
+        plan.ensure_no_failures().unwrap();
+
+        assert_eq!(fs::read_to_string(source.join("experiment.xenium")).unwrap(), "new");
+        assert_eq!(
+            fs::read_to_string(lab_staging_dir.join("XA-RUN1_slideA/xeniumranger/experiment.xenium")).unwrap(),
+            "delivered"
+        );
+    }
+
+    #[test]
+    fn fails_whole_plan_on_collision() {
+        let (_dir, lab_staging_dir) = utf8_tempdir();
+        let destination = delivered_slide(&lab_staging_dir);
+
+        let plan = Plan {
+            moves: vec![PlannedMove::new(
+                Utf8Path::new("output-M1__slideA__reg1"),
+                SLIDE,
+                lab_staging_dir.clone(),
+                "XA-RUN1_slideA",
+                None,
+                CollisionPolicy::Fail,
+            )],
+        };
+
+        let err = plan.ensure_no_failures().unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            format!(
+                "refusing to move anything: found 1 problem with the destinations:\n  - {destination} already exists"
+            )
+        );
+    }
+}
@@ -0,0 +1,166 @@
+use std::{
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use console::Term;
+
+/// How much of each slide has been moved, shared between the moves themselves and whatever is displaying them
+pub(super) struct Progress {
+    slides: Vec<Arc<SlideProgress>>,
+    n_lines_drawn: AtomicUsize,
+}
+
+pub(super) struct SlideProgress {
+    label: String,
+    total_bytes: AtomicU64,
+    pub(super) bytes_moved: AtomicU64,
+    state: Mutex<State>,
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    Waiting,
+    Moving(Instant),
+    Done(Duration),
+    Failed,
+}
+
+impl SlideProgress {
+    pub(super) fn new(label: String) -> Self {
+        Self {
+            label,
+            total_bytes: AtomicU64::new(0),
+            bytes_moved: AtomicU64::new(0),
+            state: Mutex::new(State::Waiting),
+        }
+    }
+
+    /// Reading the size of a slide's source can be slow and can fail, so it's only done once its move has started
+    pub(super) fn start(&self, total_bytes: u64) {
+        self.total_bytes.store(total_bytes, Ordering::Relaxed);
+        *self.state.lock().unwrap() = State::Moving(Instant::now());
+    }
+
+    pub(super) fn finish(&self, succeeded: bool) {
+        let mut state = self.state.lock().unwrap();
+
+        *state = match (*state, succeeded) {
+            (State::Moving(started), true) => {
+                // A move within one filesystem is a rename, which doesn't report any bytes as it goes
+                self.bytes_moved
+                    .store(self.total_bytes.load(Ordering::Relaxed), Ordering::Relaxed);
+                State::Done(started.elapsed())
+            }
+            (_, true) => State::Done(Duration::ZERO),
+            (_, false) => State::Failed,
+        };
+    }
+
+    fn line(&self) -> String {
+        let Self {
+            label,
+            total_bytes,
+            bytes_moved,
+            state,
+        } = self;
+        let total_bytes = total_bytes.load(Ordering::Relaxed);
+        let bytes_moved = bytes_moved.load(Ordering::Relaxed).min(total_bytes);
+
+        let status = match *state.lock().unwrap() {
+            State::Waiting => "waiting".to_string(),
+            State::Moving(started) => {
+                let percent = (bytes_moved * 100).checked_div(total_bytes).unwrap_or(100);
+
+                let elapsed = started.elapsed().as_secs_f64();
+                let eta = if bytes_moved == 0 || elapsed == 0.0 {
+                    "unknown".to_string()
+                } else {
+                    let bytes_per_second = bytes_moved as f64 / elapsed;
+                    format_duration(Duration::from_secs_f64(
+                        (total_bytes - bytes_moved) as f64 / bytes_per_second,
+                    ))
+                };
+
+                format!(
+                    "{} / {} ({percent}%), ETA {eta}",
+                    format_bytes(bytes_moved),
+                    format_bytes(total_bytes)
+                )
+            }
+            State::Done(elapsed) => format!("done in {}", format_duration(elapsed)),
+            State::Failed => "failed".to_string(),
+        };
+
+        format!("{label}: {status}")
+    }
+}
+
+impl Progress {
+    pub(super) fn new(slides: Vec<Arc<SlideProgress>>) -> Self {
+        Self {
+            slides,
+            n_lines_drawn: AtomicUsize::new(0),
+        }
+    }
+
+    /// How often to draw. A terminal is redrawn in place, but anything else (like a `slurm` log) is appended to, so
+    /// it's drawn less often
+    pub(super) fn interval(term: &Term) -> Duration {
+        if term.is_term() {
+            Duration::from_secs(1)
+        } else {
+            Duration::from_secs(60)
+        }
+    }
+
+    pub(super) fn draw(&self, term: &Term) -> anyhow::Result<()> {
+        let err = "failed to write progress to terminal";
+
+        if term.is_term() {
+            term.clear_last_lines(self.n_lines_drawn.load(Ordering::Relaxed))
+                .context(err)?;
+        }
+
+        for slide in &self.slides {
+            term.write_line(&slide.line()).context(err)?;
+        }
+        self.n_lines_drawn.store(self.slides.len(), Ordering::Relaxed);
+
+        Ok(())
+    }
+}
+
+fn format_bytes(n_bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = n_bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{n_bytes} B")
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+
+    if hours > 0 {
+        format!("{hours}h{minutes:02}m{seconds:02}s")
+    } else if minutes > 0 {
+        format!("{minutes}m{seconds:02}s")
+    } else {
+        format!("{seconds}s")
+    }
+}
@@ -0,0 +1,263 @@
+use std::{
+    collections::{HashMap, HashSet, hash_map::Entry},
+    fs,
+};
+
+use anyhow::{Context, anyhow};
+use camino::Utf8Path;
+use itertools::Itertools;
+
+use super::{
+    config::StagingDirSpecification,
+    experiment::{Experiment, matches_dir_name_part},
+    plan::{CollisionPolicy, PlannedMove},
+    slide::Slide,
+};
+use crate::problems::bail_on_problems;
+
+// Rerunning `xeniumranger resegment`, `relabel`, or `import-segmentation` on a slide produces an output directory
+// named by the `--id` it was run with:
+// ├── <OUTPUT ID>
+// │   ├── _cmdline
+// │   └── outs
+// │       └── experiment.xenium
+//
+// The slide is identified by `outs/experiment.xenium`, and the output is delivered next to the original, which must
+// already have been staged. Each rerun gets its own version, so a slide can be resegmented more than once:
+// └── <SLIDE ID>-<RUN ID>_<SLIDE NAME>
+//     ├── design
+//     ├── xeniumranger
+//     └── xeniumranger-<COMMAND>
+//         ├── v1_<OUTPUT ID>
+//         └── v2_<ANOTHER OUTPUT ID>
+//
+// If the slide has more than one region, the versions of each region go in their own directory under
+// `xeniumranger-<COMMAND>`.
+
+const CMDLINE_FILENAME: &str = "_cmdline";
+
+#[derive(Debug, Clone, Copy)]
+enum Command {
+    Resegment,
+    Relabel,
+    ImportSegmentation,
+}
+
+impl Command {
+    fn from_cmdline(cmdline: &str) -> Option<Self> {
+        let mut words = cmdline.split_whitespace();
+        words.find(|w| w.ends_with("xeniumranger"))?;
+
+        match words.next()? {
+            "resegment" => Some(Self::Resegment),
+            "relabel" => Some(Self::Relabel),
+            "import-segmentation" => Some(Self::ImportSegmentation),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Resegment => "resegment",
+            Self::Relabel => "relabel",
+            Self::ImportSegmentation => "import-segmentation",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(super) struct RerunDir<'a> {
+    path: &'a Utf8Path,
+    output_id: &'a str,
+    command: Command,
+    experiment: Experiment,
+}
+
+impl<'a> RerunDir<'a> {
+    pub(super) fn from_dir(dir: &'a impl AsRef<Utf8Path>) -> anyhow::Result<Self> {
+        let dir = dir.as_ref();
+        let output_id = dir
+            .file_name()
+            .ok_or(anyhow!("failed to decode xeniumranger output directory name"))?;
+
+        let cmdline_path = dir.join(CMDLINE_FILENAME);
+        let cmdline = fs::read_to_string(&cmdline_path).context(format!("failed to read {cmdline_path}"))?;
+        let command = Command::from_cmdline(&cmdline).ok_or(anyhow!(
+            "{cmdline_path} is not from xeniumranger resegment, relabel, or import-segmentation: {}",
+            cmdline.trim()
+        ))?;
+
+        let experiment = Experiment::from_output_dir(&dir.join("outs"))?;
+
+        Ok(Self {
+            path: dir,
+            output_id,
+            command,
+            experiment,
+        })
+    }
+}
+
+/// Plan the delivery of each xeniumranger rerun into the slide directory it was run on, failing on any rerun that
+/// can't be delivered
+pub(super) fn plan_moves<'b>(
+    rerun_dirs: &'b [RerunDir<'b>],
+    slides: &'b HashMap<&'b str, Vec<Slide<'b>>>,
+    staging_dir_spec: &StagingDirSpecification,
+    collision_policy: CollisionPolicy,
+) -> anyhow::Result<Vec<PlannedMove<'b>>> {
+    let mut problems = Vec::new();
+    let mut planned_moves = Vec::new();
+    let mut versions_dirs = HashMap::new();
+    let mut destinations = HashSet::new();
+
+    for RerunDir {
+        path,
+        output_id,
+        command,
+        experiment,
+    } in rerun_dirs.iter().sorted_by_key(|d| d.path)
+    {
+        let Experiment {
+            slide_id: slide_name,
+            region_name,
+            run_name: run_id,
+            ..
+        } = experiment;
+
+        // The spreadsheet's run IDs and the region directories were both taken from directory names, which the
+        // instrument sanitizes, so they're compared the same way as when the slide was staged
+        let Some(slide) = slides
+            .iter()
+            .filter(|(dir_run_id, _)| matches_dir_name_part(run_id, dir_run_id))
+            .flat_map(|(_, s)| s)
+            .find(|s| s.name == slide_name)
+        else {
+            problems.push(format!(
+                "{path} was run on slide {slide_name} from run {run_id} according to experiment.xenium, but the \
+                 spreadsheet doesn't list that slide"
+            ));
+            continue;
+        };
+
+        let lab_staging_dir = staging_dir_spec.lab_staging_dir(slide.lab_name)?;
+        let lab_staging_dir = lab_staging_dir
+            .canonicalize_utf8()
+            .context(format!("failed to get absolute path for {lab_staging_dir}"))?;
+
+        let slide_dir = lab_staging_dir.join(format!("{}-{}_{}", slide.id, slide.run_id, slide.name));
+        let xeniumranger_dir = slide_dir.join("xeniumranger");
+        if !xeniumranger_dir.is_dir() {
+            problems.push(format!(
+                "{path} was run on slide {slide_name}, but that slide hasn't been staged to {slide_dir} yet"
+            ));
+            continue;
+        }
+
+        // A slide with more than one region was delivered with a directory for each region, so its reruns are too
+        let mut versions_dir = slide_dir.join(format!("xeniumranger-{}", command.name()));
+        if let Some(region_dir_name) = find_region_dir_name(&xeniumranger_dir, region_name)? {
+            versions_dir.push(region_dir_name);
+        }
+
+        let versions = match versions_dirs.entry(versions_dir.clone()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(Versions::read(&versions_dir)?),
+        };
+        let destination = versions_dir.join(versions.name_for(output_id));
+        if !destinations.insert(destination.clone()) {
+            problems.push(format!(
+                "{path} would be delivered to {destination}, as would another xeniumranger output with the same name"
+            ));
+            continue;
+        }
+
+        planned_moves.push(PlannedMove::rerun(
+            path,
+            *slide,
+            lab_staging_dir,
+            slide_dir,
+            destination,
+            collision_policy,
+        ));
+    }
+
+    bail_on_problems("the xeniumranger outputs", &problems)?;
+
+    Ok(planned_moves)
+}
+
+/// The name of the directory under a slide's `xeniumranger` directory that `region_name` was delivered to, if the slide
+/// has more than one region
+fn find_region_dir_name(xeniumranger_dir: &Utf8Path, region_name: &str) -> anyhow::Result<Option<String>> {
+    for entry in xeniumranger_dir
+        .read_dir_utf8()
+        .context(format!("failed to read {xeniumranger_dir}"))?
+    {
+        let entry = entry?;
+
+        if entry.file_type()?.is_dir() && matches_dir_name_part(region_name, entry.file_name()) {
+            return Ok(Some(entry.file_name().to_string()));
+        }
+    }
+
+    Ok(None)
+}
+
+/// The versions already delivered to a `xeniumranger-<COMMAND>` directory, which are named `v<N>_<OUTPUT ID>`
+struct Versions {
+    latest: u32,
+    names_by_output_id: HashMap<String, String>,
+}
+
+impl Versions {
+    fn read(versions_dir: &Utf8Path) -> anyhow::Result<Self> {
+        let mut versions = Self {
+            latest: 0,
+            names_by_output_id: HashMap::new(),
+        };
+
+        if !versions_dir.exists() {
+            return Ok(versions);
+        }
+
+        for entry in versions_dir
+            .read_dir_utf8()
+            .context(format!("failed to read {versions_dir}"))?
+        {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+
+            let name = entry.file_name();
+            let Some((version, output_id)) = name.strip_prefix('v').and_then(|n| n.split_once('_')) else {
+                continue;
+            };
+            let Ok(version) = version.parse() else {
+                continue;
+            };
+
+            versions.latest = versions.latest.max(version);
+            versions
+                .names_by_output_id
+                .insert(output_id.to_string(), name.to_string());
+        }
+
+        Ok(versions)
+    }
+
+    /// The name of the version for `output_id`. An output that was already (or partly) delivered keeps its version,
+    /// so that an interrupted move is resumed and a finished one is treated as a collision
+    fn name_for(&mut self, output_id: &str) -> String {
+        if let Some(name) = self.names_by_output_id.get(output_id) {
+            return name.clone();
+        }
+
+        self.latest += 1;
+        let name = format!("v{}_{output_id}", self.latest);
+        self.names_by_output_id.insert(output_id.to_string(), name.clone());
+
+        name
+    }
+}
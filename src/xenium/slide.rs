@@ -1,4 +1,6 @@
-#[derive(bon::Builder, Debug)]
+use serde::Serialize;
+
+#[derive(bon::Builder, Debug, Clone, Copy, Serialize)]
 pub(super) struct Slide<'a> {
     pub(super) id: &'a str,
     pub(super) name: &'a str,
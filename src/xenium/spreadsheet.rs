@@ -1,9 +1,13 @@
+mod file;
+
 use std::{collections::HashMap, fmt::Display};
 
+use anyhow::anyhow;
+use camino::Utf8Path;
 use itertools::Itertools;
-use serde::{Deserialize, Deserializer};
+use serde::Deserialize;
 
-use super::{N_FIELDS, SpreadsheetSpecification, slide::Slide};
+use super::{SpreadsheetSpecification, config::ColumnIndices, slide::Slide};
 
 #[allow(dead_code)]
 #[derive(Deserialize, Debug)]
@@ -11,33 +15,55 @@ use super::{N_FIELDS, SpreadsheetSpecification, slide::Slide};
 pub(super) struct ValueRange {
     range: String,
     major_dimension: MajorDimension,
-    #[serde(deserialize_with = "vec_to_array")]
-    values: Vec<[String; N_FIELDS]>,
+    // Google Sheets leaves out trailing empty cells, so rows can have different lengths
+    #[serde(default)]
+    values: Vec<Vec<String>>,
 }
 
 impl ValueRange {
+    /// Read a local copy of the spreadsheet (CSV, XLSX, or JSON) instead of downloading it
+    pub(super) fn from_file(path: &Utf8Path, range: &str) -> anyhow::Result<Self> {
+        file::read(path, range)
+    }
+
     pub(super) fn to_xenium_slides<'a>(
         &'a self,
         spec: &SpreadsheetSpecification,
     ) -> anyhow::Result<HashMap<&'a str, Vec<Slide<'a>>>> {
         let Self { values, .. } = self;
 
-        let SpreadsheetSpecification {
-            slide_id_col_idx,
-            slide_name_col_idx,
-            run_id_col_idx,
-            lab_name_col_idx,
-            ..
-        } = spec;
+        let (header_row, rows) = if spec.has_header_row() {
+            let (header_row, rows) = values
+                .split_first()
+                .ok_or(anyhow!("the Xenium spreadsheet has no header row"))?;
+
+            (Some(header_row.as_slice()), rows)
+        } else {
+            (None, values.as_slice())
+        };
 
-        let slides = values
+        let ColumnIndices {
+            slide_id,
+            slide_name,
+            run_id,
+            lab_name,
+        } = spec.column_indices(header_row)?;
+
+        let cell = |row: &'a [String], idx: usize| row.get(idx).map(String::as_str).unwrap_or_default();
+
+        let slides = rows
             .iter()
-            .map(|array| {
+            .filter(|row| {
+                [slide_id, slide_name, run_id, lab_name]
+                    .iter()
+                    .any(|&i| !cell(row, i).is_empty())
+            })
+            .map(|row| {
                 Slide::builder()
-                    .id(&array[*slide_id_col_idx as usize])
-                    .name(&array[*slide_name_col_idx as usize])
-                    .run_id(&array[*run_id_col_idx as usize])
-                    .lab_name(&array[*lab_name_col_idx as usize])
+                    .id(cell(row, slide_id))
+                    .name(cell(row, slide_name))
+                    .run_id(cell(row, run_id))
+                    .lab_name(cell(row, lab_name))
                     .build()
             })
             .into_group_map_by(|s| s.run_id);
@@ -46,27 +72,6 @@ impl ValueRange {
     }
 }
 
-fn vec_to_array<'de, D>(deserializer: D) -> Result<Vec<[String; N_FIELDS]>, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let vec_of_vecs = <Vec<Vec<String>>>::deserialize(deserializer)?;
-
-    let to_array = |v: Vec<String>| {
-        let err = serde::de::Error::custom(format!("expected array of length {N_FIELDS}, found {v:?}"));
-
-        Ok(v.into_iter().collect_array().ok_or(err)?)
-    };
-
-    let vec_of_arrays = vec_of_vecs
-        .into_iter()
-        .filter(|v| v.len() != 0)
-        .map(to_array)
-        .try_collect()?;
-
-    Ok(vec_of_arrays)
-}
-
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "UPPERCASE")]
 pub(super) enum MajorDimension {
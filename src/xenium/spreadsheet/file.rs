@@ -0,0 +1,172 @@
+use std::fs;
+
+use anyhow::{Context, anyhow, bail, ensure};
+use calamine::{Reader, open_workbook_auto};
+use camino::Utf8Path;
+
+use super::{MajorDimension, ValueRange};
+
+// A spreadsheet file stands in for the Google Sheets API when it can't be reached. CSV and XLSX files are cropped to
+// the configured range, just as the API would crop the spreadsheet, so that the same column specification works for
+// both. A JSON file is either a `ValueRange` saved from the API or an entry from the cache directory, both of which
+// have already been cropped.
+
+/// Read `path` as if it were `range` downloaded from Google Sheets
+pub(super) fn read(path: &Utf8Path, range: &str) -> anyhow::Result<ValueRange> {
+    let extension = path.extension().unwrap_or_default().to_lowercase();
+    let a1_range = A1Range::parse(range)?;
+
+    let values = match extension.as_str() {
+        "json" => return read_json(path),
+        "csv" => read_csv(path)?,
+        "xlsx" | "xlsm" | "xls" | "ods" => read_workbook(path, a1_range.sheet_name)?,
+        _ => bail!("{path} must be a CSV, XLSX, or JSON file"),
+    };
+
+    Ok(ValueRange {
+        range: range.to_string(),
+        major_dimension: MajorDimension::Rows,
+        values: a1_range.crop(values),
+    })
+}
+
+fn read_json(path: &Utf8Path) -> anyhow::Result<ValueRange> {
+    let contents = fs::read_to_string(path).context(format!("failed to read {path}"))?;
+    let mut json: serde_json::Value = serde_json::from_str(&contents).context(format!("failed to parse {path}"))?;
+
+    // A cache entry holds the `ValueRange` in its `data` field
+    if let Some(data) = json.get_mut("data") {
+        json = data.take();
+    }
+
+    serde_json::from_value(json).context(format!("failed to parse {path} as a Google Sheets value range"))
+}
+
+fn read_csv(path: &Utf8Path) -> anyhow::Result<Vec<Vec<String>>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_path(path)
+        .context(format!("failed to open {path}"))?;
+
+    reader
+        .records()
+        .map(|record| Ok(record?.iter().map(str::to_string).collect()))
+        .collect::<anyhow::Result<_>>()
+        .context(format!("failed to read {path}"))
+}
+
+fn read_workbook(path: &Utf8Path, sheet_name: Option<&str>) -> anyhow::Result<Vec<Vec<String>>> {
+    let mut workbook = open_workbook_auto(path).context(format!("failed to open {path}"))?;
+
+    let sheet_name = match sheet_name {
+        Some(sheet_name) => sheet_name.to_string(),
+        None => workbook
+            .sheet_names()
+            .into_iter()
+            .next()
+            .ok_or(anyhow!("{path} has no sheets"))?,
+    };
+
+    let range = workbook
+        .worksheet_range(&sheet_name)
+        .context(format!("failed to read sheet {sheet_name} from {path}"))?;
+
+    // The range only covers the cells in use, so its cells are placed where they are in the sheet
+    let (Some((start_row, start_col)), Some((end_row, end_col))) = (range.start(), range.end()) else {
+        return Ok(Vec::new());
+    };
+    let mut values = vec![vec![String::new(); end_col as usize + 1]; end_row as usize + 1];
+    for (row, col, cell) in range.used_cells() {
+        values[start_row as usize + row][start_col as usize + col] = cell.to_string();
+    }
+
+    Ok(values)
+}
+
+/// A range in A1 notation, like `'Sheet name'!A2:D1000`. Either end can leave out its row, and the cells can be left
+/// out entirely to mean the whole sheet
+#[derive(Debug)]
+struct A1Range<'a> {
+    sheet_name: Option<&'a str>,
+    start: (usize, usize),
+    end: (Option<usize>, Option<usize>),
+}
+
+impl<'a> A1Range<'a> {
+    fn parse(range: &'a str) -> anyhow::Result<Self> {
+        let (sheet_name, cells) = match range.rsplit_once('!') {
+            Some((sheet_name, cells)) => (Some(sheet_name), Some(cells)),
+            // A range with no `!` is either just cells or just a sheet name
+            None if range.contains(':') => (None, Some(range)),
+            None => (Some(range), None),
+        };
+        let sheet_name = sheet_name.map(|s| s.trim_matches('\''));
+
+        let Some(cells) = cells else {
+            return Ok(Self {
+                sheet_name,
+                start: (0, 0),
+                end: (None, None),
+            });
+        };
+
+        let (start, end) = cells.split_once(':').unwrap_or((cells, cells));
+        let (start_row, start_col) = parse_cell(start).context(format!("failed to parse range {range}"))?;
+        let (end_row, end_col) = parse_cell(end).context(format!("failed to parse range {range}"))?;
+
+        Ok(Self {
+            sheet_name,
+            start: (start_row.unwrap_or_default(), start_col.unwrap_or_default()),
+            end: (end_row, end_col),
+        })
+    }
+
+    fn crop(&self, values: Vec<Vec<String>>) -> Vec<Vec<String>> {
+        let Self {
+            start: (start_row, start_col),
+            end: (end_row, end_col),
+            ..
+        } = *self;
+
+        values
+            .into_iter()
+            .enumerate()
+            .filter(|(i, _)| *i >= start_row && end_row.is_none_or(|end| *i <= end))
+            .map(|(_, row)| {
+                row.into_iter()
+                    .enumerate()
+                    .filter(|(j, _)| *j >= start_col && end_col.is_none_or(|end| *j <= end))
+                    .map(|(_, cell)| cell)
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+/// Parse a cell like `D1000` into its zero-based row and column, either of which can be left out
+fn parse_cell(cell: &str) -> anyhow::Result<(Option<usize>, Option<usize>)> {
+    let split = cell.find(|c: char| c.is_ascii_digit()).unwrap_or(cell.len());
+    let (letters, digits) = cell.split_at(split);
+
+    ensure!(letters.chars().all(|c| c.is_ascii_alphabetic()), "invalid cell {cell}");
+
+    let col = (!letters.is_empty()).then(|| {
+        letters
+            .to_ascii_uppercase()
+            .bytes()
+            .fold(0, |col, b| col * 26 + usize::from(b - b'A' + 1))
+            - 1
+    });
+
+    let row = match digits {
+        "" => None,
+        digits => {
+            let row: usize = digits.parse().context(format!("invalid cell {cell}"))?;
+            ensure!(row > 0, "invalid cell {cell}");
+            Some(row - 1)
+        }
+    };
+
+    Ok((row, col))
+}
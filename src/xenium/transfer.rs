@@ -0,0 +1,515 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, FileTimes, OpenOptions},
+    io::{self, Read, Write},
+    os::unix::fs::symlink,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use anyhow::{Context, anyhow, ensure};
+use camino::{Utf8Path, Utf8PathBuf};
+use sha2::{Digest, Sha256};
+
+const BUFFER_SIZE: usize = 8 * 1024 * 1024;
+
+// The instrument and the staging directory are on different filesystems, so a move is really a copy followed by a
+// removal. Every file that has been copied and verified is appended to a journal next to the destination, in the same
+// format as `sha256sum`, so that a move that was interrupted (for example, by a `slurm` job hitting its time limit)
+// picks up where it stopped.
+//
+// Once everything has been copied, the journal records that the copy is complete, and the source is renamed to a
+// hidden sibling before it's removed. A source that was only partly removed when the move was interrupted is therefore
+// never mistaken for one that still needs copying: the next run finishes removing it instead. The journal is removed
+// last.
+
+/// Marks the line of a journal that records that the whole directory was copied, in place of a checksum
+const COPY_COMPLETE: &str = "complete";
+
+/// The path of the journal that records the progress of moving a directory to `destination`
+pub(super) fn journal_path(destination: &Utf8Path) -> Utf8PathBuf {
+    let name = destination.file_name().unwrap_or_default();
+
+    destination.with_file_name(format!(".{name}.journal"))
+}
+
+/// The path that `source` is renamed to once it has been copied, while it's being removed
+pub(super) fn removal_path(source: &Utf8Path) -> Utf8PathBuf {
+    let name = source.file_name().unwrap_or_default();
+
+    source.with_file_name(format!(".{name}.moved"))
+}
+
+/// Whether `path` is a source that was copied but not completely removed before a move was interrupted
+pub(super) fn is_removal_path(path: &Utf8Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.starts_with('.') && name.ends_with(".moved"))
+}
+
+/// Finish removing a source that was left at `removal_path` when a move was interrupted
+pub(super) fn finish_removal(removal_path: &Utf8Path) -> anyhow::Result<()> {
+    fs::remove_dir_all(removal_path).context(format!("failed to remove {removal_path}"))
+}
+
+/// Move `source` to `destination`, renaming it if they're on the same filesystem and copying it otherwise. Copied
+/// files keep their permissions and modification times, and each one is checked against the original by size and
+/// SHA-256 checksum before the original is removed. Returns the checksums of the copied files, which is empty if
+/// `source` was renamed. The number of bytes copied so far is kept in `bytes_moved`.
+pub(super) fn move_dir(
+    source: &Utf8Path,
+    destination: &Utf8Path,
+    bytes_moved: &AtomicU64,
+) -> anyhow::Result<HashMap<Utf8PathBuf, String>> {
+    let journal_path = journal_path(destination);
+
+    // Renaming is only an option if the destination doesn't exist yet, which isn't the case once a copy has started or
+    // when merging into an earlier delivery
+    if !journal_path.exists() && !destination.exists() {
+        match fs::rename(source, destination) {
+            Ok(()) => return Ok(HashMap::new()),
+            Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {}
+            Err(e) => return Err(e).context(format!("failed to move {source} to {destination}")),
+        }
+    }
+
+    copy_and_remove(source, destination, bytes_moved)
+}
+
+fn copy_and_remove(
+    source: &Utf8Path,
+    destination: &Utf8Path,
+    bytes_moved: &AtomicU64,
+) -> anyhow::Result<HashMap<Utf8PathBuf, String>> {
+    let journal_path = journal_path(destination);
+    let mut journal = Journal::open(&journal_path)?;
+
+    if !journal.is_copy_complete {
+        copy_dir(source, destination, &mut journal, bytes_moved)
+            .context(format!("failed to copy {source} to {destination}"))?;
+        journal.record_copy_complete(source)?;
+    }
+
+    let removal_path = removal_path(source);
+    fs::rename(source, &removal_path).context(format!(
+        "failed to rename {source} to {removal_path} after copying it to {destination}"
+    ))?;
+    fs::remove_dir_all(&removal_path).context(format!(
+        "failed to remove {removal_path} after copying it to {destination}"
+    ))?;
+    fs::remove_file(&journal_path).context(format!("failed to remove {journal_path}"))?;
+
+    Ok(journal.checksums)
+}
+
+fn copy_dir(
+    source: &Utf8Path,
+    destination: &Utf8Path,
+    journal: &mut Journal,
+    bytes_moved: &AtomicU64,
+) -> anyhow::Result<()> {
+    fs::create_dir_all(destination).context(format!("failed to create {destination}"))?;
+
+    for entry in source.read_dir_utf8().context(format!("failed to read {source}"))? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let (source, destination) = (entry.path(), destination.join(entry.file_name()));
+
+        if file_type.is_dir() {
+            copy_dir(source, &destination, journal, bytes_moved)?;
+        } else if file_type.is_symlink() {
+            copy_symlink(source, &destination)?;
+        } else if journal.is_complete(source, &destination) {
+            bytes_moved.fetch_add(entry.metadata()?.len(), Ordering::Relaxed);
+        } else {
+            let checksum = copy_file(source, &destination, bytes_moved)?;
+            journal.record(&destination, &checksum)?;
+        }
+    }
+
+    // Copying files into a directory changes its modification time, so it can only be copied once the directory is
+    // full
+    copy_metadata(source, destination)
+}
+
+fn copy_symlink(source: &Utf8Path, destination: &Utf8Path) -> anyhow::Result<()> {
+    let target = fs::read_link(source).context(format!("failed to read symbolic link {source}"))?;
+
+    if destination.symlink_metadata().is_ok() {
+        fs::remove_file(destination).context(format!("failed to remove {destination}"))?;
+    }
+
+    symlink(&target, destination).context(format!("failed to create symbolic link {destination}"))
+}
+
+/// Copy `source` to `destination`, returning the checksum that both share
+fn copy_file(source: &Utf8Path, destination: &Utf8Path, bytes_moved: &AtomicU64) -> anyhow::Result<String> {
+    let mut reader = File::open(source).context(format!("failed to open {source}"))?;
+
+    // A partial copy from an interrupted move may already have been made read-only
+    if destination.exists() {
+        fs::remove_file(destination).context(format!("failed to remove partial copy {destination}"))?;
+    }
+    let mut writer = File::create(destination).context(format!("failed to create {destination}"))?;
+
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut n_bytes_copied = 0;
+    loop {
+        let n_bytes = reader.read(&mut buffer).context(format!("failed to read {source}"))?;
+        if n_bytes == 0 {
+            break;
+        }
+
+        hasher.update(&buffer[..n_bytes]);
+        writer
+            .write_all(&buffer[..n_bytes])
+            .context(format!("failed to write {destination}"))?;
+        n_bytes_copied += n_bytes as u64;
+        bytes_moved.fetch_add(n_bytes as u64, Ordering::Relaxed);
+    }
+    writer.sync_all().context(format!("failed to write {destination}"))?;
+
+    let source_size = reader.metadata()?.len();
+    ensure!(
+        n_bytes_copied == source_size,
+        "{source} changed size while it was being copied"
+    );
+
+    let source_checksum = to_hex(&hasher.finalize());
+    verify_copy(source, source_size, &source_checksum, destination)?;
+
+    copy_metadata(source, destination)?;
+
+    Ok(source_checksum)
+}
+
+/// Check `destination` against the size and checksum of the `source` it was copied from, reading it back from disk
+fn verify_copy(
+    source: &Utf8Path,
+    source_size: u64,
+    source_checksum: &str,
+    destination: &Utf8Path,
+) -> anyhow::Result<()> {
+    let (destination_size, destination_checksum) = sha256_file(destination)?;
+    ensure!(
+        destination_size == source_size,
+        "{destination} is {destination_size} bytes, but {source} is {source_size} bytes"
+    );
+    ensure!(
+        destination_checksum == source_checksum,
+        "the SHA-256 checksum of {destination} ({destination_checksum}) does not match that of {source} \
+         ({source_checksum})"
+    );
+
+    Ok(())
+}
+
+fn copy_metadata(source: &Utf8Path, destination: &Utf8Path) -> anyhow::Result<()> {
+    let metadata = fs::metadata(source).context(format!("failed to read metadata for {source}"))?;
+
+    let times = FileTimes::new()
+        .set_accessed(metadata.accessed()?)
+        .set_modified(metadata.modified()?);
+    File::open(destination)
+        .and_then(|f| f.set_times(times))
+        .context(format!("failed to set modification time of {destination}"))?;
+
+    // Permissions come last, since they may make the destination read-only
+    fs::set_permissions(destination, metadata.permissions())
+        .context(format!("failed to set permissions of {destination}"))
+}
+
+/// The total size of the regular files under `dir`
+pub(super) fn dir_size(dir: &Utf8Path) -> anyhow::Result<u64> {
+    let mut size = 0;
+    for entry in dir.read_dir_utf8().context(format!("failed to read {dir}"))? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+
+        if file_type.is_dir() {
+            size += dir_size(entry.path())?;
+        } else if file_type.is_file() {
+            size += entry.metadata()?.len();
+        }
+    }
+
+    Ok(size)
+}
+
+/// The size and hex-encoded SHA-256 checksum of the file at `path`
+pub(super) fn sha256_file(path: &Utf8Path) -> anyhow::Result<(u64, String)> {
+    let mut file = File::open(path).context(format!("failed to open {path}"))?;
+
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut n_bytes_read = 0;
+    loop {
+        let n_bytes = file.read(&mut buffer).context(format!("failed to read {path}"))?;
+        if n_bytes == 0 {
+            break;
+        }
+
+        hasher.update(&buffer[..n_bytes]);
+        n_bytes_read += n_bytes as u64;
+    }
+
+    Ok((n_bytes_read, to_hex(&hasher.finalize())))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+struct Journal {
+    file: File,
+    checksums: HashMap<Utf8PathBuf, String>,
+    is_copy_complete: bool,
+}
+
+impl Journal {
+    fn open(path: &Utf8Path) -> anyhow::Result<Self> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e).context(format!("failed to read journal {path}")),
+        };
+
+        let mut checksums = HashMap::new();
+        let mut is_copy_complete = false;
+        for line in contents.lines() {
+            let (checksum, destination) = line
+                .split_once("  ")
+                .ok_or(anyhow!("malformed line in journal {path}: {line}"))?;
+
+            if checksum == COPY_COMPLETE {
+                is_copy_complete = true;
+            } else {
+                checksums.insert(Utf8PathBuf::from(destination), checksum.to_string());
+            }
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .context(format!("failed to open journal {path}"))?;
+
+        Ok(Self {
+            file,
+            checksums,
+            is_copy_complete,
+        })
+    }
+
+    /// Whether `destination` was copied before an interruption. The checksum was verified at the time, so only the
+    /// size is checked again in case the file was touched since
+    fn is_complete(&self, source: &Utf8Path, destination: &Utf8Path) -> bool {
+        if !self.checksums.contains_key(destination) {
+            return false;
+        }
+
+        match (fs::metadata(source), fs::metadata(destination)) {
+            (Ok(s), Ok(d)) => s.len() == d.len(),
+            _ => false,
+        }
+    }
+
+    fn record(&mut self, destination: &Utf8Path, checksum: &str) -> anyhow::Result<()> {
+        self.write_line(checksum, destination)?;
+        self.checksums.insert(destination.to_path_buf(), checksum.to_string());
+
+        Ok(())
+    }
+
+    /// Record that every file in `source` has been copied, so that it's safe to start removing it
+    fn record_copy_complete(&mut self, source: &Utf8Path) -> anyhow::Result<()> {
+        self.write_line(COPY_COMPLETE, source)?;
+        self.is_copy_complete = true;
+
+        Ok(())
+    }
+
+    fn write_line(&mut self, checksum: &str, path: &Utf8Path) -> anyhow::Result<()> {
+        writeln!(self.file, "{checksum}  {path}")
+            .and_then(|_| self.file.sync_data())
+            .context("failed to write to journal")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        os::unix::fs::{MetadataExt, PermissionsExt},
+        time::{Duration, SystemTime},
+    };
+
+    use pretty_assertions::assert_eq;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn utf8_tempdir() -> (TempDir, Utf8PathBuf) {
+        let dir = TempDir::new().unwrap();
+        let path = Utf8PathBuf::from_path_buf(dir.path().to_path_buf()).unwrap();
+
+        (dir, path)
+    }
+
+    fn sha256(contents: &str) -> String {
+        to_hex(&Sha256::digest(contents))
+    }
+
+    /// A source directory with a nested file and a symbolic link, returning its path
+    fn write_source(root: &Utf8Path) -> Utf8PathBuf {
+        let source = root.join("output-M1__slide__region");
+        fs::create_dir_all(source.join("cells")).unwrap();
+        fs::write(source.join("experiment.xenium"), "experiment").unwrap();
+        fs::write(source.join("cells/cells.csv"), "cells").unwrap();
+        symlink("cells/cells.csv", source.join("cells.csv")).unwrap();
+
+        source
+    }
+
+    #[test]
+    fn copies_and_removes_source() {
+        let (_dir, root) = utf8_tempdir();
+        let source = write_source(&root);
+        let destination = root.join("xeniumranger");
+
+        let bytes_moved = AtomicU64::new(0);
+        let checksums = copy_and_remove(&source, &destination, &bytes_moved).unwrap();
+
+        assert_eq!(
+            checksums,
+            HashMap::from([
+                (destination.join("experiment.xenium"), sha256("experiment")),
+                (destination.join("cells/cells.csv"), sha256("cells")),
+            ])
+        );
+        assert_eq!(fs::read_to_string(destination.join("cells.csv")).unwrap(), "cells");
+        assert_eq!(
+            fs::read_link(destination.join("cells.csv")).unwrap().to_str(),
+            Some("cells/cells.csv")
+        );
+        assert_eq!(bytes_moved.load(Ordering::Relaxed), 15);
+
+        assert!(!source.exists());
+        assert!(!removal_path(&source).exists());
+        assert!(!journal_path(&destination).exists());
+    }
+
+    #[test]
+    fn resumes_interrupted_copy() {
+        let (_dir, root) = utf8_tempdir();
+        let source = write_source(&root);
+        let destination = root.join("xeniumranger");
+
+        // Interrupt the move after one file has been copied
+        fs::create_dir_all(&destination).unwrap();
+        let copied = destination.join("experiment.xenium");
+        let checksum = copy_file(&source.join("experiment.xenium"), &copied, &AtomicU64::new(0)).unwrap();
+        Journal::open(&journal_path(&destination))
+            .unwrap()
+            .record(&copied, &checksum)
+            .unwrap();
+        let inode = fs::metadata(&copied).unwrap().ino();
+
+        let checksums = copy_and_remove(&source, &destination, &AtomicU64::new(0)).unwrap();
+
+        assert_eq!(
+            fs::metadata(&copied).unwrap().ino(),
+            inode,
+            "copied file was copied again"
+        );
+        assert_eq!(checksums.len(), 2);
+        assert_eq!(
+            fs::read_to_string(destination.join("cells/cells.csv")).unwrap(),
+            "cells"
+        );
+        assert!(!source.exists());
+        assert!(!journal_path(&destination).exists());
+    }
+
+    #[test]
+    fn resumes_interrupted_removal() {
+        let (_dir, root) = utf8_tempdir();
+        let source = write_source(&root);
+        let destination = root.join("xeniumranger");
+
+        // Interrupt the move once everything has been copied, but before the source has been removed
+        let mut journal = Journal::open(&journal_path(&destination)).unwrap();
+        copy_dir(&source, &destination, &mut journal, &AtomicU64::new(0)).unwrap();
+        journal.record_copy_complete(&source).unwrap();
+        fs::remove_file(destination.join("cells/cells.csv")).unwrap();
+
+        let checksums = copy_and_remove(&source, &destination, &AtomicU64::new(0)).unwrap();
+
+        assert!(
+            !destination.join("cells/cells.csv").exists(),
+            "complete copy was copied again"
+        );
+        assert_eq!(checksums.len(), 2);
+        assert!(!source.exists());
+
+        // Interrupt the removal itself, leaving a partly removed source behind
+        let source = write_source(&root);
+        fs::rename(&source, removal_path(&source)).unwrap();
+        fs::remove_file(removal_path(&source).join("experiment.xenium")).unwrap();
+
+        assert!(is_removal_path(&removal_path(&source)));
+        finish_removal(&removal_path(&source)).unwrap();
+        assert!(!removal_path(&source).exists());
+    }
+
+    #[test]
+    fn rejects_mismatched_copies() {
+        let (_dir, root) = utf8_tempdir();
+        let source = root.join("source");
+        let destination = root.join("destination");
+        fs::write(&source, "contents").unwrap();
+
+        fs::write(&destination, "contents").unwrap();
+        verify_copy(&source, 8, &sha256("contents"), &destination).unwrap();
+
+        fs::write(&destination, "content").unwrap();
+        let err = verify_copy(&source, 8, &sha256("contents"), &destination).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("{destination} is 7 bytes, but {source} is 8 bytes")
+        );
+
+        fs::write(&destination, "CONTENTS").unwrap();
+        let err = verify_copy(&source, 8, &sha256("contents"), &destination).unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with(&format!("the SHA-256 checksum of {destination}"))
+        );
+    }
+
+    #[test]
+    fn preserves_modification_times_and_permissions() {
+        let (_dir, root) = utf8_tempdir();
+        let source = write_source(&root);
+        let destination = root.join("xeniumranger");
+
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let file = source.join("cells/cells.csv");
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o640)).unwrap();
+        File::open(&file)
+            .unwrap()
+            .set_times(FileTimes::new().set_modified(modified))
+            .unwrap();
+        fs::set_permissions(source.join("cells"), fs::Permissions::from_mode(0o750)).unwrap();
+        File::open(source.join("cells"))
+            .unwrap()
+            .set_times(FileTimes::new().set_modified(modified))
+            .unwrap();
+
+        copy_and_remove(&source, &destination, &AtomicU64::new(0)).unwrap();
+
+        for (path, mode) in [("cells/cells.csv", 0o640), ("cells", 0o750)] {
+            let metadata = fs::metadata(destination.join(path)).unwrap();
+            assert_eq!(metadata.modified().unwrap(), modified, "{path}");
+            assert_eq!(metadata.permissions().mode() & 0o777, mode, "{path}");
+        }
+    }
+}